@@ -1,8 +1,14 @@
 #[cfg(test)]
 mod tests;
 
-use crate::encryption::EncryptionSystem;
-use derive_getters::Getters;
+mod asynchronous;
+
+pub use asynchronous::{decrypt_source_async, encrypt_source_async, rewind_source_async};
+
+use crate::encryption;
+use crate::encryption::{EncryptionContext, EncryptionSystem};
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
 use fs::read_to_string;
 use im::Vector;
 use lazy_static::lazy_static;
@@ -11,72 +17,364 @@ use regex::Regex;
 use std::error::Error;
 use std::fmt::Display;
 use std::fs::{OpenOptions, exists};
-use std::io::Read;
+use std::io::{IsTerminal, Read, Write};
+use std::ops::Range;
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::process::Command;
 use std::rc::Rc;
 use std::string::FromUtf8Error;
-use std::{fs, iter};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::{fs, iter, thread};
 
 pub const STDIO: &str = "-";
 
 lazy_static! {
-    static ref MARKER_RE: Regex = Regex::new(r"<<(/?(SECURE|CIPHER))>>").unwrap();
+    static ref MARKER_RE: Regex = Regex::new(
+        r#"<<(/?(?:SECURE|CIPHER))((?:\s+[A-Za-z_][A-Za-z0-9_.-]*=(?:"[^"]*"|[^\s">]+))*)\s*>>"#
+    )
+    .unwrap();
+    static ref ATTRIBUTE_RE: Regex =
+        Regex::new(r#"([A-Za-z_][A-Za-z0-9_.-]*)=(?:"([^"]*)"|([^\s">]+))"#).unwrap();
 }
 
-#[derive(Debug, Getters, PartialEq, Clone)]
-pub struct AppError {
-    context: String,
-    detail: String,
+/// Boxed source error kept by `AppError` variants that wrap another error.
+pub type BoxedError = Box<dyn Error + Send + Sync + 'static>;
+
+/// Errors produced by the library.  The variants separate the kinds of failures
+/// callers are likely to want to handle differently.  Wrapped errors are
+/// available through `Error::source()`.
+#[derive(Debug)]
+pub enum AppError {
+    /// Invalid command line arguments or configuration.
+    Usage(String),
+    /// The markers in a source file are malformed.  `line` and `column` are 1 based
+    /// and `text` is the line containing the problem.
+    Parse {
+        message: String,
+        line: usize,
+        column: usize,
+        text: String,
+    },
+    /// Reading or writing a file or stream failed.
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    /// Credentials were missing or rejected, or access to a key was denied.
+    AccessDenied {
+        context: String,
+        detail: String,
+        source: Option<BoxedError>,
+    },
+    /// A ciphertext could not be decoded or failed authentication.  Either it was
+    /// modified or the wrong key or encryption context was used to decrypt it.
+    Tampered {
+        context: String,
+        detail: String,
+        source: Option<BoxedError>,
+    },
+    /// Any other failure while encrypting or decrypting.
+    Encryption {
+        context: String,
+        detail: String,
+        source: Option<BoxedError>,
+    },
+    /// A ciphertext names a key (see `EncryptionSystem::key_id()`) that is not configured.
+    UnknownKey { context: String, key_id: String },
 }
 
 impl Display for AppError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "error: context: {} detail: {}",
-            self.context, self.detail
-        )
+        match self {
+            AppError::Usage(detail) => write!(f, "usage error: {}", detail),
+            AppError::Parse {
+                message,
+                line,
+                column,
+                text,
+            } => {
+                let caret: String = text
+                    .chars()
+                    .take(column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(
+                    f,
+                    "parse error: {} at line {} column {}\n{}\n{}^",
+                    message, line, column, text, caret
+                )
+            }
+            AppError::Io { context, source } => write!(f, "io error: {}: {}", context, source),
+            AppError::AccessDenied {
+                context, detail, ..
+            } => write!(f, "access denied: {}: {}", context, detail),
+            AppError::Tampered {
+                context, detail, ..
+            } => write!(f, "invalid ciphertext: {}: {}", context, detail),
+            AppError::Encryption {
+                context, detail, ..
+            } => write!(f, "error: {}: {}", context, detail),
+            AppError::UnknownKey { context, key_id } => write!(
+                f,
+                "unknown key: {}: encrypted with key {} which is not configured",
+                context, key_id
+            ),
+        }
     }
 }
 
-impl Error for AppError {}
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AppError::Io { source, .. } => Some(source),
+            AppError::AccessDenied { source, .. }
+            | AppError::Tampered { source, .. }
+            | AppError::Encryption { source, .. } => source
+                .as_ref()
+                .map(|e| e.as_ref() as &(dyn Error + 'static)),
+            AppError::Usage(_) | AppError::Parse { .. } | AppError::UnknownKey { .. } => None,
+        }
+    }
+}
 
 impl AppError {
+    /// Creates an `Encryption` error without a source.
     pub fn from_str(context: &str, detail: &str) -> Self {
-        Self {
+        AppError::Encryption {
             context: context.to_string(),
             detail: detail.to_string(),
+            source: None,
         }
     }
 
-    pub fn from_error<E: Error>(context: &str, e: E) -> Self {
-        Self {
+    /// Creates an `Encryption` error wrapping `e`.
+    pub fn from_error<E: Error + Send + Sync + 'static>(context: &str, e: E) -> Self {
+        AppError::Encryption {
             context: context.to_string(),
             detail: e.to_string(),
+            source: Some(Box::new(e)),
+        }
+    }
+
+    pub fn usage(detail: &str) -> Self {
+        AppError::Usage(detail.to_string())
+    }
+
+    pub fn tampered(context: &str, detail: &str) -> Self {
+        AppError::Tampered {
+            context: context.to_string(),
+            detail: detail.to_string(),
+            source: None,
+        }
+    }
+
+    pub fn access_denied(context: &str, detail: &str) -> Self {
+        AppError::AccessDenied {
+            context: context.to_string(),
+            detail: detail.to_string(),
+            source: None,
+        }
+    }
+
+    pub fn unknown_key(context: &str, key_id: &str) -> Self {
+        AppError::UnknownKey {
+            context: context.to_string(),
+            key_id: key_id.to_string(),
+        }
+    }
+
+    /// Adds the location of a block, such as `block 3 (db.password)`, to the
+    /// error's context.  Parse errors already carry their location.
+    pub fn in_block(self, block: &str) -> Self {
+        let prefix = |context: String| format!("{}: {}", block, context);
+        match self {
+            AppError::Usage(detail) => AppError::Usage(prefix(detail)),
+            AppError::Parse { .. } => self,
+            AppError::Io { context, source } => AppError::Io {
+                context: prefix(context),
+                source,
+            },
+            AppError::AccessDenied {
+                context,
+                detail,
+                source,
+            } => AppError::AccessDenied {
+                context: prefix(context),
+                detail,
+                source,
+            },
+            AppError::Tampered {
+                context,
+                detail,
+                source,
+            } => AppError::Tampered {
+                context: prefix(context),
+                detail,
+                source,
+            },
+            AppError::Encryption {
+                context,
+                detail,
+                source,
+            } => AppError::Encryption {
+                context: prefix(context),
+                detail,
+                source,
+            },
+            AppError::UnknownKey { context, key_id } => AppError::UnknownKey {
+                context: prefix(context),
+                key_id,
+            },
         }
     }
 }
 
 impl From<std::io::Error> for AppError {
     fn from(error: std::io::Error) -> Self {
-        AppError::from_error("IO Error", &error)
+        AppError::Io {
+            context: "input/output".to_string(),
+            source: error,
+        }
     }
 }
 
 impl From<FromUtf8Error> for AppError {
     fn from(error: FromUtf8Error) -> Self {
-        AppError::from_error("utf8 decode error", &error)
+        AppError::from_error("utf8 decode error", error)
+    }
+}
+
+/// Name/value pairs from the opening marker of a block such as
+/// `<<SECURE name="db.password">>`.  Order is preserved so that markers are
+/// written back out the same way they were read.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes(Vector<(String, String)>);
+
+/// Prefix for attributes whose values are added to the encryption context of
+/// their block.  For example `context.env="prod"` adds `env=prod`.
+pub const CONTEXT_ATTRIBUTE_PREFIX: &str = "context.";
+
+/// Attribute that marks a block as holding binary data and the one value it supports.
+pub const ENCODING_ATTRIBUTE: &str = "encoding";
+pub const BASE64_ENCODING: &str = "base64";
+
+/// Attribute used to look up a specific block.
+pub const NAME_ATTRIBUTE: &str = "name";
+
+/// Checks that an attribute is one that is understood and that it can be
+/// written in a marker and read back.  Unknown attributes are refused rather
+/// than ignored so that a block is never treated differently than its marker
+/// suggests.  Returns a description of the problem.
+fn check_attribute(name: &str, value: &str) -> Result<(), String> {
+    let known = match name.strip_prefix(CONTEXT_ATTRIBUTE_PREFIX) {
+        Some(key) => {
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+        }
+        None => name == NAME_ATTRIBUTE || name == ENCODING_ATTRIBUTE,
+    };
+    if !known {
+        return Err(format!("unknown attribute {}", name));
+    }
+    if value.contains('"') {
+        return Err(format!("value of attribute {} cannot contain '\"'", name));
+    }
+    Ok(())
+}
+
+impl Attributes {
+    pub fn new() -> Self {
+        Self(Vector::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of the `name` attribute used to look up a specific block.
+    pub fn name(&self) -> Option<&str> {
+        self.get(NAME_ATTRIBUTE)
+    }
+
+    /// Returns a copy with `name` set to `value`, replacing any existing value.
+    /// Fails for attributes other than `name`, `encoding` and `context.<key>`,
+    /// and for values containing `"` which cannot be written in a marker.
+    pub fn with(&self, name: &str, value: &str) -> Result<Self, AppError> {
+        check_attribute(name, value).map_err(|e| AppError::usage(e.as_str()))?;
+        let mut answer = self.clone();
+        match answer.0.iter().position(|(n, _)| n == name) {
+            Some(i) => {
+                answer.0.set(i, (name.to_string(), value.to_string()));
+            }
+            None => answer.0.push_back((name.to_string(), value.to_string())),
+        }
+        Ok(answer)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// True when the block holds base64 encoded binary data (`encoding=base64`)
+    /// rather than text.
+    pub fn is_binary(&self) -> Result<bool, AppError> {
+        match self.get(ENCODING_ATTRIBUTE) {
+            None => Ok(false),
+            Some(BASE64_ENCODING) => Ok(true),
+            Some(other) => Err(AppError::usage(
+                format!("unsupported encoding {}", other).as_str(),
+            )),
+        }
+    }
+
+    /// The encryption context for a block: `base` plus any `context.` attributes.
+    pub fn context(&self, base: &EncryptionContext) -> EncryptionContext {
+        let mut answer = base.clone();
+        for (name, value) in self.iter() {
+            if let Some(key) = name.strip_prefix(CONTEXT_ATTRIBUTE_PREFIX) {
+                answer.insert(key.to_string(), value.clone());
+            }
+        }
+        answer
+    }
+}
+
+impl Display for Attributes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, " {}=\"{}\"", name, value)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Segment {
-    Secure(String),
-    Cipher(String),
+    Secure(String, Attributes),
+    Cipher(String, Attributes),
     Text(String),
 }
 
+impl Segment {
+    /// The attributes of a `SECURE` or `CIPHER` block.
+    pub fn attributes(&self) -> Option<&Attributes> {
+        match self {
+            Segment::Secure(_, attributes) | Segment::Cipher(_, attributes) => Some(attributes),
+            Segment::Text(_) => None,
+        }
+    }
+}
+
 pub type Segments = Vector<Rc<Segment>>;
 
 fn random_chars() -> String {
@@ -118,67 +416,291 @@ fn create_temp_file(path: &str) -> Result<String, AppError> {
             .open(&temp_path)?;
         return Ok(temp_path);
     }
-    Err(AppError::from_str("output", "Failed to create temp file"))
+    Err(AppError::Io {
+        context: "output".to_string(),
+        source: std::io::Error::other("Failed to create temp file"),
+    })
 }
 
-fn encrypt(segments: Segments, system: &dyn EncryptionSystem) -> Result<Segments, AppError> {
-    let mut answer: Segments = Vector::new();
-    for seg in segments.iter() {
-        match seg.as_ref() {
-            Segment::Secure(plain) => {
-                let cipher = system.encrypt(plain)?;
-                answer.push_back(Rc::new(Segment::Cipher(cipher)));
-            }
-            _ => answer.push_back(Rc::clone(seg)),
+/// Maximum number of blocks that are encrypted or decrypted at the same time.
+const MAX_CONCURRENT_BLOCKS: usize = 8;
+
+/// Applies `f` to every item using up to `MAX_CONCURRENT_BLOCKS` threads and
+/// returns the results in the same order as `items`.  Once any call fails no
+/// new items are started and the first error (in item order) is returned.
+fn parallel_map<T, R, F>(items: &[T], f: F) -> Result<Vec<R>, AppError>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, AppError> + Sync,
+{
+    if items.len() <= 1 {
+        return items.iter().map(&f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Vec<Mutex<Option<Result<R, AppError>>>> =
+        items.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..MAX_CONCURRENT_BLOCKS.min(items.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= items.len() {
+                        break;
+                    }
+                    let result = f(&items[index]);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    *results[index].lock().unwrap() = Some(result);
+                }
+            });
+        }
+    });
+    let mut answer = Vec::with_capacity(items.len());
+    for result in results {
+        match result.into_inner().unwrap() {
+            Some(Ok(value)) => answer.push(value),
+            Some(Err(error)) => return Err(error),
+            None => break,
         }
     }
     Ok(answer)
 }
 
-fn rewind(segments: Segments, system: &dyn EncryptionSystem) -> Result<Segments, AppError> {
+/// Picks the blocks a pass works on, returning their text and attributes.
+type BlockSelector = fn(&Segment) -> Option<(&String, &Attributes)>;
+
+/// Creates the replacement for a block from the processed text and its attributes.
+type BlockBuilder = fn(String, Attributes) -> Segment;
+
+/// The text and encryption context of a block, whether it holds binary data,
+/// and a label such as `block 3 (db.password)` used in error messages.
+struct BlockJob {
+    text: String,
+    context: EncryptionContext,
+    binary: bool,
+    label: String,
+}
+
+/// Describes the block at `index` for error messages using its 1 based position
+/// among the blocks (as accepted by `find_block()`) and its name if it has one.
+fn block_label(segments: &Segments, index: usize) -> String {
+    let ordinal = segments
+        .iter()
+        .take(index + 1)
+        .filter(|s| is_block(s))
+        .count();
+    match segments[index].attributes().and_then(|a| a.name()) {
+        Some(name) => format!("block {} ({})", ordinal, name),
+        None => format!("block {}", ordinal),
+    }
+}
+
+/// Returns the jobs for every segment that `select` picks.
+fn block_jobs(
+    segments: &Segments,
+    context: &EncryptionContext,
+    select: BlockSelector,
+) -> Result<Vec<BlockJob>, AppError> {
+    segments
+        .iter()
+        .enumerate()
+        .filter_map(|(index, seg)| select(seg).map(|block| (index, block)))
+        .map(|(index, (text, attributes))| {
+            let label = block_label(segments, index);
+            Ok(BlockJob {
+                text: text.clone(),
+                context: attributes.context(context),
+                binary: attributes.is_binary().map_err(|e| e.in_block(&label))?,
+                label,
+            })
+        })
+        .collect()
+}
+
+/// Decodes the base64 text of a binary block.  Whitespace is ignored so the
+/// output of tools that wrap long lines can be pasted in, and both the standard
+/// and URL safe alphabets are accepted.
+fn decode_binary(text: &str) -> Result<Vec<u8>, AppError> {
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    STANDARD
+        .decode(&compact)
+        .or_else(|_| URL_SAFE.decode(&compact))
+        .map_err(|e| AppError::usage(format!("invalid base64 in binary block: {}", e).as_str()))
+}
+
+fn encode_binary(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+fn encrypt_block(system: &dyn EncryptionSystem, job: &BlockJob) -> Result<String, AppError> {
+    if job.binary {
+        system.encrypt_bytes(&decode_binary(&job.text)?, &job.context)
+    } else {
+        system.encrypt(&job.text, &job.context)
+    }
+}
+
+fn decrypt_block(system: &dyn EncryptionSystem, job: &BlockJob) -> Result<String, AppError> {
+    if job.binary {
+        Ok(encode_binary(
+            &system.decrypt_bytes(&job.text, &job.context)?,
+        ))
+    } else {
+        system.decrypt(&job.text, &job.context)
+    }
+}
+
+/// Replaces each segment that `select` picks with the segment `build` creates
+/// from the next value in `results`.  `results` must be in segment order.
+fn replace_blocks(
+    segments: Segments,
+    select: BlockSelector,
+    results: Vec<String>,
+    build: BlockBuilder,
+) -> Segments {
+    let mut results = results.into_iter();
     let mut answer: Segments = Vector::new();
     for seg in segments.iter() {
-        match seg.as_ref() {
-            Segment::Cipher(cipher) => {
-                let plain = system.decrypt(cipher)?;
-                answer.push_back(Rc::new(Segment::Secure(plain)));
+        match select(seg) {
+            Some((_, attributes)) => {
+                let result = results.next().unwrap();
+                answer.push_back(Rc::new(build(result, attributes.clone())));
             }
-            _ => answer.push_back(Rc::clone(seg)),
+            None => answer.push_back(Rc::clone(seg)),
         }
     }
-    Ok(answer)
+    answer
+}
+
+/// Replaces each segment that `select` picks with the segment `build` creates from
+/// the result of calling `operation` on its job (see `BlockJob`).  The
+/// operations run concurrently (see `parallel_map()`) but segment order is kept.
+fn map_blocks<F>(
+    segments: Segments,
+    context: &EncryptionContext,
+    select: BlockSelector,
+    operation: F,
+    build: BlockBuilder,
+) -> Result<Segments, AppError>
+where
+    F: Fn(&BlockJob) -> Result<String, AppError> + Sync,
+{
+    let jobs = block_jobs(&segments, context, select)?;
+    let results = parallel_map(&jobs, |job| {
+        operation(job).map_err(|e| e.in_block(&job.label))
+    })?;
+    Ok(replace_blocks(segments, select, results, build))
+}
+
+fn secure_block(segment: &Segment) -> Option<(&String, &Attributes)> {
+    match segment {
+        Segment::Secure(plain, attributes) => Some((plain, attributes)),
+        _ => None,
+    }
+}
+
+fn cipher_block(segment: &Segment) -> Option<(&String, &Attributes)> {
+    match segment {
+        Segment::Cipher(cipher, attributes) => Some((cipher, attributes)),
+        _ => None,
+    }
+}
+
+fn encrypt(
+    segments: Segments,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<Segments, AppError> {
+    map_blocks(
+        segments,
+        context,
+        secure_block,
+        |job| encrypt_block(system, job),
+        Segment::Cipher,
+    )
+}
+
+fn rewind(
+    segments: Segments,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<Segments, AppError> {
+    map_blocks(
+        segments,
+        context,
+        cipher_block,
+        |job| decrypt_block(system, job),
+        Segment::Secure,
+    )
+}
+
+/// Re-encrypts every Cipher segment with `to`, decrypting it with `from`.
+/// Values move between the systems as bytes so binary blocks are never
+/// decoded, and blocks whose envelope already names `to`'s key are kept as
+/// they are.  Key ids that are only a scheme, such as `pass:`, do not name a
+/// particular key (every passphrase has the same one) so those blocks are
+/// always re-encrypted.
+fn rotate(
+    segments: Segments,
+    from: &dyn EncryptionSystem,
+    to: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<Segments, AppError> {
+    let target = to.key_id().filter(|key_id| !key_id.ends_with(':'));
+    map_blocks(
+        segments,
+        context,
+        cipher_block,
+        |job| {
+            if let Some((key_id, _)) = encryption::parse_envelope(&job.text)?
+                && target.as_deref() == Some(key_id)
+            {
+                return Ok(job.text.clone());
+            }
+            to.encrypt_bytes(&from.decrypt_bytes(&job.text, &job.context)?, &job.context)
+        },
+        Segment::Cipher,
+    )
 }
 
-fn decrypt(segments: Segments, system: &dyn EncryptionSystem) -> Result<Segments, AppError> {
+fn decrypt(
+    segments: Segments,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<Segments, AppError> {
+    let rewound = rewind(segments, system, context)?;
     let mut answer: Segments = Vector::new();
-    for seg in segments.iter() {
+    for seg in rewound.iter() {
         match seg.as_ref() {
-            Segment::Cipher(cipher) => {
-                let plain = system.decrypt(cipher)?;
-                answer.push_back(Rc::new(Segment::Text(plain)));
-            }
-            Segment::Secure(plain) => {
+            // binary blocks stay as they are so that `expand()` can decode them
+            Segment::Secure(plain, attributes) if !attributes.is_binary()? => {
                 answer.push_back(Rc::new(Segment::Text(plain.clone())));
             }
-            Segment::Text(_) => answer.push_back(Rc::clone(seg)),
+            _ => answer.push_back(Rc::clone(seg)),
         }
     }
     Ok(answer)
 }
 
-/// Converts a vector of segments into a String.  The vector must only contain
-/// Text and Secure segments.
-fn expand(segments: Segments) -> Result<String, AppError> {
-    let mut answer = String::new();
+/// Converts a vector of segments into bytes.  The vector must only contain
+/// Text and Secure segments.  Binary Secure segments are decoded.
+fn expand(segments: Segments) -> Result<Vec<u8>, AppError> {
+    let mut answer = Vec::new();
     for seg in segments.iter() {
         match seg.as_ref() {
             Segment::Text(text) => {
-                answer += text;
+                answer.extend_from_slice(text.as_bytes());
             }
-            Segment::Secure(plain) => {
-                answer += plain;
+            Segment::Secure(plain, attributes) if attributes.is_binary()? => {
+                answer.extend(decode_binary(plain)?);
             }
-            Segment::Cipher(_) => {
+            Segment::Secure(plain, _) => {
+                answer.extend_from_slice(plain.as_bytes());
+            }
+            Segment::Cipher(_, _) => {
                 return Err(AppError::from_str(
                     "expand",
                     "Encountered Cipher segment during expansion:",
@@ -198,13 +720,13 @@ fn combine(segments: Segments) -> Result<String, AppError> {
             Segment::Text(text) => {
                 answer += text;
             }
-            Segment::Secure(plain) => {
-                answer += "<<SECURE>>";
+            Segment::Secure(plain, attributes) => {
+                answer += format!("<<SECURE{}>>", attributes).as_str();
                 answer += plain;
                 answer += "<</SECURE>>";
             }
-            Segment::Cipher(cipher) => {
-                answer += "<<CIPHER>>";
+            Segment::Cipher(cipher, attributes) => {
+                answer += format!("<<CIPHER{}>>", attributes).as_str();
                 answer += cipher;
                 answer += "<</CIPHER>>";
             }
@@ -213,45 +735,121 @@ fn combine(segments: Segments) -> Result<String, AppError> {
     Ok(answer)
 }
 
+/// Parses the attributes of a marker.  Returns a description of the problem
+/// if the attributes are invalid.
+fn parse_attributes(source: &str) -> Result<Attributes, String> {
+    let mut answer = Attributes::new();
+    for captures in ATTRIBUTE_RE.captures_iter(source) {
+        let name = &captures[1];
+        if answer.get(name).is_some() {
+            return Err(format!("duplicate attribute {}", name));
+        }
+        let value = captures.get(2).or_else(|| captures.get(3)).unwrap();
+        check_attribute(name, value.as_str())?;
+        answer = answer
+            .with(name, value.as_str())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(answer)
+}
+
+/// Returns the 1 based line and column of the byte at `offset` in `source`.
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+/// Creates a parsing error for the problem at `offset` in `source`.
+fn parse_error(source: &str, offset: usize, message: &str) -> AppError {
+    let (line, column) = line_and_column(source, offset);
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    AppError::Parse {
+        message: message.to_string(),
+        line,
+        column,
+        text: source[line_start..line_end]
+            .trim_end_matches('\r')
+            .to_string(),
+    }
+}
+
 fn parse_source(source: String) -> Result<Segments, AppError> {
+    let (segments, _) = parse_with_spans(&source)?;
+    Ok(segments)
+}
+
+/// Parses `source` into segments and also returns the byte range within
+/// `source` occupied by each segment (including its markers).
+fn parse_with_spans(source: &str) -> Result<(Segments, Vec<Range<usize>>), AppError> {
     let mut offset: usize = 0;
     let mut expected: Option<String> = None;
+    let mut attributes = Attributes::new();
+    let mut block_start: usize = 0;
     let mut answer = Vector::<Rc<Segment>>::new();
+    let mut spans = Vec::new();
     loop {
-        match MARKER_RE.captures_at(source.as_str(), offset) {
+        match MARKER_RE.captures_at(source, offset) {
             Some(captures) => {
                 let m = captures.get(0).unwrap();
+                let content_start = offset;
                 let content = source[offset..m.start()].to_string();
                 let marker = captures[1].to_string();
+                let marker_attributes = parse_attributes(&captures[2])
+                    .map_err(|e| parse_error(source, m.start(), &e))?;
                 offset = m.end();
                 match &expected {
                     Some(s) => {
                         if s != &marker {
-                            return Err(AppError::from_str(
-                                "parsing",
-                                format!("expected {} but found {}", s, marker).as_str(),
+                            let (line, column) = line_and_column(source, block_start);
+                            return Err(parse_error(
+                                source,
+                                m.start(),
+                                format!(
+                                    "expected {} to close the block started at line {} column {} but found {}",
+                                    s, line, column, marker
+                                )
+                                .as_str(),
                             ));
                         }
+                        if !marker_attributes.is_empty() {
+                            return Err(parse_error(
+                                source,
+                                m.start(),
+                                format!("end tag {} cannot have attributes", marker).as_str(),
+                            ));
+                        }
+                        let block_attributes = std::mem::take(&mut attributes);
                         let segment = if s == "/SECURE" {
-                            Segment::Secure(content)
+                            Segment::Secure(content, block_attributes)
                         } else {
-                            Segment::Cipher(content)
+                            Segment::Cipher(content, block_attributes)
                         };
                         answer.push_back(Rc::new(segment));
+                        spans.push(block_start..m.end());
                         expected = None
                     }
                     None => {
                         if !content.is_empty() {
                             let segment = Segment::Text(content);
                             answer.push_back(Rc::new(segment));
+                            spans.push(content_start..m.start());
                         }
+                        attributes = marker_attributes;
+                        block_start = m.start();
                         if marker == "SECURE" {
                             expected = Some("/SECURE".to_string())
                         } else if marker == "CIPHER" {
                             expected = Some("/CIPHER".to_string())
                         } else {
-                            return Err(AppError::from_str(
-                                "parsing",
+                            return Err(parse_error(
+                                source,
+                                m.start(),
                                 format!("expected start tag but found {}", marker).as_str(),
                             ));
                         }
@@ -260,35 +858,48 @@ fn parse_source(source: String) -> Result<Segments, AppError> {
             }
             None => {
                 if let Some(s) = expected {
-                    return Err(AppError::from_str(
-                        "parsing",
-                        format!("expected {} but found end of string", s).as_str(),
+                    return Err(parse_error(
+                        source,
+                        block_start,
+                        format!(
+                            "expected {} but found end of string for the block started",
+                            s
+                        )
+                        .as_str(),
                     ));
                 }
                 if offset < source.len() {
                     let text = source[offset..].to_string();
                     let segment = Segment::Text(text);
                     answer.push_back(Rc::new(segment));
+                    spans.push(offset..source.len());
                 }
                 break;
             }
         }
     }
-    Ok(answer)
+    Ok((answer, spans))
 }
 
-fn load_file(filename: &str) -> Result<Vector<Rc<Segment>>, AppError> {
+fn read_source(filename: &str) -> Result<String, AppError> {
     let mut source: String;
     if filename == STDIO {
         source = String::new();
         std::io::stdin().read_to_string(&mut source)?;
     } else {
-        source = read_to_string(filename)?;
+        source = read_to_string(filename).map_err(|e| AppError::Io {
+            context: filename.to_string(),
+            source: e,
+        })?;
     }
-    parse_source(source)
+    Ok(source)
+}
+
+fn load_file(filename: &str) -> Result<Vector<Rc<Segment>>, AppError> {
+    parse_source(read_source(filename)?)
 }
 
-fn write_file(filename: &str, contents: &String) -> Result<(), AppError> {
+fn write_file(filename: &str, contents: impl AsRef<[u8]>) -> Result<(), AppError> {
     Ok(fs::write(filename, contents)?)
 }
 
@@ -298,8 +909,8 @@ fn replace_file(temp_file: &str, real_file: &str) -> Result<(), AppError> {
 
 fn write_result(temp_file: &str, real_file: &str) -> Result<(), AppError> {
     if real_file == STDIO {
-        let s = read_to_string(temp_file)?;
-        Ok(print!("{}", s))
+        std::io::stdout().write_all(&fs::read(temp_file)?)?;
+        Ok(())
     } else {
         replace_file(temp_file, real_file)
     }
@@ -312,11 +923,164 @@ fn delete_file(temp_file: &str) -> Result<(), AppError> {
     Ok(())
 }
 
-pub fn cat_command(input_filename: &str, system: &dyn EncryptionSystem) -> Result<(), AppError> {
+pub fn keygen_command(output_filename: &str) -> Result<(), AppError> {
+    if output_filename == STDIO {
+        return Err(AppError::usage("keygen requires an output file name"));
+    }
+    encryption::generate_key_file(output_filename)
+}
+
+fn is_block(segment: &Segment) -> bool {
+    !matches!(segment, Segment::Text(_))
+}
+
+/// Find the block whose `name` attribute matches `name`.  If no block has that
+/// name and `name` is a number the block at that (1 based) position among all
+/// of the `SECURE` and `CIPHER` blocks in the file is used instead.
+fn find_block(segments: &Segments, name: &str) -> Result<usize, AppError> {
+    let mut named = segments
+        .iter()
+        .enumerate()
+        .filter(|(_, seg)| match seg.as_ref() {
+            Segment::Secure(_, attributes) | Segment::Cipher(_, attributes) => {
+                attributes.name() == Some(name)
+            }
+            Segment::Text(_) => false,
+        })
+        .map(|(index, _)| index);
+    if let Some(index) = named.next() {
+        return match named.next() {
+            None => Ok(index),
+            Some(_) => Err(AppError::usage(
+                format!("more than one block is named {}", name).as_str(),
+            )),
+        };
+    }
+    name.parse::<usize>()
+        .ok()
+        .filter(|ordinal| *ordinal > 0)
+        .and_then(|ordinal| {
+            segments
+                .iter()
+                .enumerate()
+                .filter(|(_, seg)| is_block(seg))
+                .nth(ordinal - 1)
+                .map(|(index, _)| index)
+        })
+        .ok_or_else(|| AppError::usage(format!("no block named {}", name).as_str()))
+}
+
+/// Returns the plaintext of the block identified by `name` (see `find_block()`).
+/// Only that one block is decrypted.  Binary blocks return the decoded bytes.
+fn get_value(
+    segments: &Segments,
+    name: &str,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<Vec<u8>, AppError> {
+    let index = find_block(segments, name)?;
+    match segments[index].as_ref() {
+        Segment::Cipher(cipher, attributes) => system
+            .decrypt_bytes(cipher, &attributes.context(context))
+            .map_err(|e| e.in_block(&block_label(segments, index))),
+        Segment::Secure(plain, attributes) if attributes.is_binary()? => decode_binary(plain),
+        Segment::Secure(plain, _) => Ok(plain.as_bytes().to_vec()),
+        Segment::Text(_) => Err(AppError::usage("not a block")),
+    }
+}
+
+pub fn get_command(
+    input_filename: &str,
+    name: &str,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<(), AppError> {
     let segments = load_file(input_filename)?;
-    let decrypted = decrypt(segments, system)?;
+    let value = get_value(&segments, name, system, context)?;
+    std::io::stdout().write_all(&value)?;
+    Ok(())
+}
+
+/// Returns a copy of `source` with the block identified by `name` (see `find_block()`)
+/// replaced by a `CIPHER` block containing the encrypted `value`.  The block keeps
+/// its attributes and everything else in `source` is left exactly as it was.
+/// Binary blocks store `value` as is.  For text blocks `value` must be UTF-8 and a
+/// single trailing line ending is removed, as left by `echo` or a piped file.
+fn set_value(
+    source: &str,
+    name: &str,
+    value: &[u8],
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<String, AppError> {
+    let (segments, spans) = parse_with_spans(source)?;
+    let index = find_block(&segments, name)?;
+    let attributes = match segments[index].as_ref() {
+        Segment::Secure(_, attributes) | Segment::Cipher(_, attributes) => attributes.clone(),
+        Segment::Text(_) => return Err(AppError::usage("not a block")),
+    };
+    let cipher = if attributes.is_binary()? {
+        system.encrypt_bytes(value, &attributes.context(context))?
+    } else {
+        let value = String::from_utf8(value.to_vec())?;
+        let trimmed = value
+            .strip_suffix("\r\n")
+            .or_else(|| value.strip_suffix('\n'))
+            .unwrap_or(&value);
+        system.encrypt(trimmed, &attributes.context(context))?
+    };
+    let block = combine(Vector::unit(Rc::new(Segment::Cipher(cipher, attributes))))?;
+    let span = &spans[index];
+    Ok(format!(
+        "{}{}{}",
+        &source[..span.start],
+        block,
+        &source[span.end..]
+    ))
+}
+
+/// Reads the new value for `set` from the terminal without echoing it, or else
+/// from stdin.
+fn read_value() -> Result<Vec<u8>, AppError> {
+    if std::io::stdin().is_terminal() {
+        Ok(rpassword::prompt_password("Value: ")?.into_bytes())
+    } else {
+        let mut value = Vec::new();
+        std::io::stdin().read_to_end(&mut value)?;
+        Ok(value)
+    }
+}
+
+pub fn set_command(
+    input_filename: &str,
+    name: &str,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<(), AppError> {
+    if input_filename == STDIO {
+        return Err(AppError::usage("set requires a file name"));
+    }
+    let source = read_source(input_filename)?;
+    let value = read_value()?;
+    let contents = set_value(&source, name, &value, system, context)?;
+    let temp_filename = create_temp_file(input_filename)?;
+    defer! {
+        delete_file(&temp_filename).unwrap_or(());
+    }
+    write_file(&temp_filename, &contents)?;
+    replace_file(&temp_filename, input_filename)?;
+    Ok(())
+}
+
+pub fn cat_command(
+    input_filename: &str,
+    system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<(), AppError> {
+    let segments = load_file(input_filename)?;
+    let decrypted = decrypt(segments, system, context)?;
     let expanded = expand(decrypted)?;
-    print!("{}", expanded);
+    std::io::stdout().write_all(&expanded)?;
     Ok(())
 }
 
@@ -324,9 +1088,10 @@ pub fn decrypt_command(
     input_filename: &str,
     output_filename: &str,
     system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
 ) -> Result<(), AppError> {
     let segments = load_file(input_filename)?;
-    let decrypted = decrypt(segments, system)?;
+    let decrypted = decrypt(segments, system, context)?;
     let expanded = expand(decrypted)?;
     let temp_filename = create_temp_file(input_filename)?;
     defer! {
@@ -341,9 +1106,10 @@ pub fn encrypt_command(
     input_filename: &str,
     output_filename: &str,
     system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
 ) -> Result<(), AppError> {
     let segments = load_file(input_filename)?;
-    let encrypted = encrypt(segments, system)?;
+    let encrypted = encrypt(segments, system, context)?;
     let contents = combine(encrypted)?;
     let temp_filename = create_temp_file(input_filename)?;
     defer! {
@@ -358,9 +1124,10 @@ pub fn rewind_command(
     input_filename: &str,
     output_filename: &str,
     system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
 ) -> Result<(), AppError> {
     let segments = load_file(input_filename)?;
-    let rewound = rewind(segments, system)?;
+    let rewound = rewind(segments, system, context)?;
     let contents = combine(rewound)?;
     let temp_filename = create_temp_file(input_filename)?;
     defer! {
@@ -371,14 +1138,91 @@ pub fn rewind_command(
     Ok(())
 }
 
+/// Re-encrypts every `CIPHER` block in the input file with `to`.  The
+/// plaintext is only ever held in memory and the output is written atomically.
+pub fn rotate_command(
+    input_filename: &str,
+    output_filename: &str,
+    from: &dyn EncryptionSystem,
+    to: &dyn EncryptionSystem,
+    context: &EncryptionContext,
+) -> Result<(), AppError> {
+    let segments = load_file(input_filename)?;
+    let rotated = rotate(segments, from, to, context)?;
+    let contents = combine(rotated)?;
+    let temp_filename = create_temp_file(input_filename)?;
+    defer! {
+        delete_file(&temp_filename).unwrap_or(());
+    }
+    write_file(&temp_filename, &contents)?;
+    write_result(&temp_filename, output_filename)?;
+    Ok(())
+}
+
+/// Asks a yes or no question on the terminal.  An empty answer means yes.
+/// Always answers no when stdin is not a terminal since nobody can respond.
+fn confirm(prompt: &str) -> Result<bool, AppError> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{}", prompt);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer.is_empty() || answer == "y" || answer == "yes")
+}
+
+/// Moves `temp_file` to a new file next to `path` that only the owner can
+/// read so that edits containing plaintext secrets are not lost.  Returns
+/// the name of the new file.
+fn keep_recovery_file(temp_file: &str, path: &str) -> Result<String, AppError> {
+    let (_, dir, name) = get_temp_file_specs(path)?;
+    fs::set_permissions(temp_file, fs::Permissions::from_mode(0o600))?;
+    for _index in 0..50 {
+        let recovery_path = format!("{}_cipher_recovered_{}_{}", dir, random_chars(), name);
+        if exists(&recovery_path)? {
+            continue;
+        }
+        replace_file(temp_file, &recovery_path)?;
+        return Ok(recovery_path);
+    }
+    Err(AppError::Io {
+        context: "output".to_string(),
+        source: std::io::Error::other("Failed to create recovery file"),
+    })
+}
+
+/// Variables checked in order to find the editor to use for `edit_command()`.
+const EDITOR_VARIABLES: [&str; 3] = ["CIPHER_EDITOR", "VISUAL", "EDITOR"];
+const DEFAULT_EDITOR: &str = "vi";
+
+/// Returns the program and arguments for the editor named by the first of the
+/// `EDITOR_VARIABLES` that `lookup` finds a non-blank value for, or `vi` if none
+/// of them are set.  Values are split using shell quoting rules so that
+/// editors that need arguments such as `code --wait` work as expected.
+fn editor_command<F: Fn(&str) -> Option<String>>(lookup: F) -> Result<Vec<String>, AppError> {
+    let value = EDITOR_VARIABLES
+        .iter()
+        .filter_map(|name| lookup(name))
+        .find(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string());
+    let words = shell_words::split(&value)
+        .map_err(|e| AppError::usage(format!("invalid editor command: {}", e).as_str()))?;
+    if words.is_empty() {
+        return Err(AppError::usage("editor command is empty"));
+    }
+    Ok(words)
+}
+
 pub fn edit_command(
     input_filename: &str,
     output_filename: &str,
     system: &dyn EncryptionSystem,
+    context: &EncryptionContext,
 ) -> Result<(), AppError> {
     // set up a rewound temp file for the editor
     let orig_segments = load_file(input_filename)?;
-    let orig_rewound = rewind(orig_segments, system)?;
+    let orig_rewound = rewind(orig_segments, system, context)?;
     let orig_contents = combine(orig_rewound)?;
     let temp_filename = create_temp_file(input_filename)?;
     defer! {
@@ -386,22 +1230,45 @@ pub fn edit_command(
     }
     write_file(&temp_filename, &orig_contents)?;
 
-    // run the editor on the temp file
-    let status = Command::new("vi").arg(&temp_filename).spawn()?.wait()?;
-    if !status.success() {
-        return Err(AppError::from_str("edit command", "editor command failed"));
-    }
+    // run the editor on the temp file until it produces a file we can parse
+    let editor = editor_command(|name| std::env::var(name).ok())?;
+    let new_segments = loop {
+        let status = Command::new(&editor[0])
+            .args(&editor[1..])
+            .arg(&temp_filename)
+            .spawn()?
+            .wait()?;
+        if !status.success() {
+            return Err(AppError::from_str("edit command", "editor command failed"));
+        }
+        match load_file(&temp_filename) {
+            Ok(segments) => break segments,
+            Err(error) => {
+                eprintln!("{}", error);
+                if !confirm("The edited file is not valid. Re-open the editor to fix it? [Y/n] ")? {
+                    let recovery_filename = keep_recovery_file(&temp_filename, input_filename)?;
+                    return Err(AppError::from_str(
+                        "edit command",
+                        format!(
+                            "the edited file is not valid, your changes have been saved in {}",
+                            recovery_filename
+                        )
+                        .as_str(),
+                    ));
+                }
+            }
+        }
+    };
 
     // see if the file was changed
-    let new_segments = load_file(&temp_filename)?;
-    let new_rewound = rewind(new_segments.clone(), system)?;
+    let new_rewound = rewind(new_segments.clone(), system, context)?;
     let new_contents = combine(new_rewound)?;
     if orig_contents == new_contents {
         return Ok(());
     }
 
     // encrypt the modified temp file and store it as the output file
-    let encrypted = encrypt(new_segments, system)?;
+    let encrypted = encrypt(new_segments, system, context)?;
     let encrypted_contents = combine(encrypted)?;
     write_file(&temp_filename, &encrypted_contents)?;
     write_result(&temp_filename, output_filename)?;
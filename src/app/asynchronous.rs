@@ -0,0 +1,102 @@
+use super::{
+    AppError, BlockBuilder, BlockJob, BlockSelector, MAX_CONCURRENT_BLOCKS, Segment, Segments,
+    block_jobs, cipher_block, combine, decode_binary, encode_binary, expand, parse_source,
+    replace_blocks, secure_block,
+};
+use crate::encryption::{AsyncEncryptionSystem, EncryptionContext};
+use futures::{StreamExt, TryStreamExt, stream};
+
+async fn run_job<S: AsyncEncryptionSystem + ?Sized>(
+    system: &S,
+    job: &BlockJob,
+    decrypting: bool,
+) -> Result<String, AppError> {
+    let result = match (decrypting, job.binary) {
+        (true, true) => system
+            .decrypt_bytes_async(&job.text, &job.context)
+            .await
+            .map(|bytes| encode_binary(&bytes)),
+        (true, false) => system.decrypt_async(&job.text, &job.context).await,
+        (false, true) => match decode_binary(&job.text) {
+            Ok(bytes) => system.encrypt_bytes_async(&bytes, &job.context).await,
+            Err(error) => Err(error),
+        },
+        (false, false) => system.encrypt_async(&job.text, &job.context).await,
+    };
+    result.map_err(|e| e.in_block(&job.label))
+}
+
+/// Runs `encrypt_async()` (or `decrypt_async()` when `decrypting` is true) on
+/// every job with at most `MAX_CONCURRENT_BLOCKS` in flight at a time.  Results
+/// are returned in the same order as `jobs`.
+async fn run_jobs<S: AsyncEncryptionSystem + ?Sized>(
+    jobs: Vec<BlockJob>,
+    system: &S,
+    decrypting: bool,
+) -> Result<Vec<String>, AppError> {
+    let futures: Vec<_> = jobs
+        .iter()
+        .map(|job| run_job(system, job, decrypting))
+        .collect();
+    stream::iter(futures)
+        .buffered(MAX_CONCURRENT_BLOCKS)
+        .try_collect()
+        .await
+}
+
+/// Parses `source`, runs the selected blocks through `system` and rebuilds the
+/// segments.  The segments are not `Send` so they are parsed again after the
+/// blocks are processed rather than being held across the await, which keeps
+/// the returned futures usable with `tokio::spawn()`.
+async fn map_source<S: AsyncEncryptionSystem + ?Sized>(
+    source: &str,
+    system: &S,
+    context: &EncryptionContext,
+    decrypting: bool,
+) -> Result<Segments, AppError> {
+    let (select, build): (BlockSelector, BlockBuilder) = if decrypting {
+        (cipher_block, Segment::Secure)
+    } else {
+        (secure_block, Segment::Cipher)
+    };
+    let jobs = block_jobs(&parse_source(source.to_string())?, context, select)?;
+    let results = run_jobs(jobs, system, decrypting).await?;
+    Ok(replace_blocks(
+        parse_source(source.to_string())?,
+        select,
+        results,
+        build,
+    ))
+}
+
+/// Encrypts every `SECURE` block in `source` and returns the text with `CIPHER`
+/// blocks in their place.  This is the async equivalent of the `encrypt` command.
+pub async fn encrypt_source_async<S: AsyncEncryptionSystem + ?Sized>(
+    source: &str,
+    system: &S,
+    context: &EncryptionContext,
+) -> Result<String, AppError> {
+    combine(map_source(source, system, context, false).await?)
+}
+
+/// Decrypts every `CIPHER` block in `source` and returns the text with `SECURE`
+/// blocks in their place.  This is the async equivalent of the `rewind` command.
+pub async fn rewind_source_async<S: AsyncEncryptionSystem + ?Sized>(
+    source: &str,
+    system: &S,
+    context: &EncryptionContext,
+) -> Result<String, AppError> {
+    combine(map_source(source, system, context, true).await?)
+}
+
+/// Decrypts every `CIPHER` block in `source` and returns the plain text without
+/// any markers.  This is the async equivalent of the `cat` command and is meant
+/// for programs that decrypt their configuration at startup.  The result is
+/// bytes since binary blocks are decoded.
+pub async fn decrypt_source_async<S: AsyncEncryptionSystem + ?Sized>(
+    source: &str,
+    system: &S,
+    context: &EncryptionContext,
+) -> Result<Vec<u8>, AppError> {
+    expand(map_source(source, system, context, true).await?)
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::test_util::temp_path;
 
 use im::vector;
 
@@ -24,11 +25,11 @@ To tell your name the livelong day<</CIPHER>>
 To an admiring bog!"
         .to_string();
     let answer = parse_source(source).unwrap();
-    let expected: Segments = vector!(Segment::Secure("I'm nobody! ".to_string()),
+    let expected: Segments = vector!(Segment::Secure("I'm nobody! ".to_string(), Attributes::new()),
             Segment::Text("Who are you?\nAre you nobody, too?\nThen there's a ".to_string()),
-            Segment::Secure("pair of us - don't tell!".to_string()),
+            Segment::Secure("pair of us - don't tell!".to_string(), Attributes::new()),
             Segment::Text("\nThey'd banish us, you know.\n\n".to_string()),
-            Segment::Cipher("How dreary to be somebody!\nHow public, like a frog\nTo tell your name the livelong day".to_string()),
+            Segment::Cipher("How dreary to be somebody!\nHow public, like a frog\nTo tell your name the livelong day".to_string(), Attributes::new()),
             Segment::Text("\nTo an admiring bog!".to_string())
         ).iter().map(|s| Rc::new(s.clone())).collect();
     assert_eq!(expected, answer);
@@ -38,18 +39,18 @@ To an admiring bog!"
 fn test_encrypt() {
     let segments = vector!(
         Rc::new(Segment::Text("abc".to_string())),
-        Rc::new(Segment::Secure("def".to_string())),
-        Rc::new(Segment::Cipher("ghi".to_string())),
+        Rc::new(Segment::Secure("def".to_string(), Attributes::new())),
+        Rc::new(Segment::Cipher("ghi".to_string(), Attributes::new())),
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let expected = vector!(
         Rc::new(Segment::Text("abc".to_string())),
-        Rc::new(Segment::Cipher("ZGVm".to_string())),
-        Rc::new(Segment::Cipher("ghi".to_string())),
+        Rc::new(Segment::Cipher("ZGVm".to_string(), Attributes::new())),
+        Rc::new(Segment::Cipher("ghi".to_string(), Attributes::new())),
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let system = crate::encryption::new_insecure_encryption().unwrap();
-    let expanded = encrypt(segments, system.as_ref()).unwrap();
+    let expanded = encrypt(segments, system.as_ref(), &EncryptionContext::new()).unwrap();
     assert_eq!(expanded, expected);
 }
 
@@ -57,18 +58,18 @@ fn test_encrypt() {
 fn test_rewind() {
     let segments = vector!(
         Rc::new(Segment::Text("abc".to_string())),
-        Rc::new(Segment::Cipher("ZGVm".to_string())),
-        Rc::new(Segment::Secure("ghi".to_string())),
+        Rc::new(Segment::Cipher("ZGVm".to_string(), Attributes::new())),
+        Rc::new(Segment::Secure("ghi".to_string(), Attributes::new())),
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let expected = vector!(
         Rc::new(Segment::Text("abc".to_string())),
-        Rc::new(Segment::Secure("def".to_string())),
-        Rc::new(Segment::Secure("ghi".to_string())),
+        Rc::new(Segment::Secure("def".to_string(), Attributes::new())),
+        Rc::new(Segment::Secure("ghi".to_string(), Attributes::new())),
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let system = crate::encryption::new_insecure_encryption().unwrap();
-    let expanded = rewind(segments, system.as_ref()).unwrap();
+    let expanded = rewind(segments, system.as_ref(), &EncryptionContext::new()).unwrap();
     assert_eq!(expanded, expected);
 }
 
@@ -76,8 +77,8 @@ fn test_rewind() {
 fn test_decrypt() {
     let segments = vector!(
         Rc::new(Segment::Text("abc".to_string())),
-        Rc::new(Segment::Cipher("ZGVm".to_string())),
-        Rc::new(Segment::Secure("ghi".to_string())),
+        Rc::new(Segment::Cipher("ZGVm".to_string(), Attributes::new())),
+        Rc::new(Segment::Secure("ghi".to_string(), Attributes::new())),
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let expected = vector!(
@@ -87,7 +88,7 @@ fn test_decrypt() {
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let system = crate::encryption::new_insecure_encryption().unwrap();
-    let expanded = decrypt(segments, system.as_ref()).unwrap();
+    let expanded = decrypt(segments, system.as_ref(), &EncryptionContext::new()).unwrap();
     assert_eq!(expanded, expected);
 }
 
@@ -95,9 +96,563 @@ fn test_decrypt() {
 fn test_expand() {
     let segments = vector!(
         Rc::new(Segment::Text("abc".to_string())),
-        Rc::new(Segment::Secure("def".to_string())),
+        Rc::new(Segment::Secure("def".to_string(), Attributes::new())),
         Rc::new(Segment::Text("xyz".to_string()))
     );
     let expanded = expand(segments).unwrap();
-    assert_eq!(expanded, "abcdefxyz".to_string());
+    assert_eq!(expanded, b"abcdefxyz");
+}
+
+/// Returns a system for a newly generated key file, which is already deleted.
+fn key_file_system() -> Box<dyn EncryptionSystem> {
+    let key_path = temp_path("key");
+    encryption::generate_key_file(&key_path).unwrap();
+    let system = encryption::create_key_file_encryption(&key_path);
+    fs::remove_file(&key_path).unwrap();
+    system.unwrap()
+}
+
+#[test]
+fn test_key_file_round_trip() {
+    let system = key_file_system();
+
+    let source = "user: <<SECURE>>fred<</SECURE>>\npassword: <<SECURE>>secret<</SECURE>>\n";
+    let segments = parse_source(source.to_string()).unwrap();
+    let encrypted = encrypt(segments.clone(), system.as_ref(), &EncryptionContext::new()).unwrap();
+    let contents = combine(encrypted.clone()).unwrap();
+    assert!(!contents.contains("fred"));
+    assert!(!contents.contains("secret"));
+    assert!(!contents.contains("<<SECURE>>"));
+
+    let rewound = rewind(
+        parse_source(contents).unwrap(),
+        system.as_ref(),
+        &EncryptionContext::new(),
+    )
+    .unwrap();
+    assert_eq!(rewound, segments);
+
+    let decrypted = decrypt(encrypted, system.as_ref(), &EncryptionContext::new()).unwrap();
+    assert_eq!(
+        expand(decrypted).unwrap(),
+        b"user: fred\npassword: secret\n"
+    );
+}
+
+#[test]
+fn test_context_mismatch() {
+    let system =
+        crate::encryption::create_passphrase_encryption_with_params("pw", 64, 1, 1).unwrap();
+    let prod = crate::encryption::parse_context("env=prod").unwrap();
+    let staging = crate::encryption::parse_context("env=staging").unwrap();
+
+    let segments = parse_source("password: <<SECURE>>secret<</SECURE>>".to_string()).unwrap();
+    let encrypted = encrypt(segments.clone(), system.as_ref(), &prod).unwrap();
+    assert_eq!(
+        rewind(encrypted.clone(), system.as_ref(), &prod).unwrap(),
+        segments
+    );
+    assert!(rewind(encrypted.clone(), system.as_ref(), &staging).is_err());
+    assert!(decrypt(encrypted, system.as_ref(), &EncryptionContext::new()).is_err());
+}
+
+#[test]
+fn test_marker_attributes() {
+    let source = r#"a: <<SECURE name="db.password" context.env=prod>>secret<</SECURE>>
+b: <<CIPHER  name="b" >>ZGVm<</CIPHER>>
+c: <<SECURE>>plain<</SECURE>>"#
+        .to_string();
+    let answer = parse_source(source).unwrap();
+    let expected: Segments = vector!(
+        Segment::Text("a: ".to_string()),
+        Segment::Secure(
+            "secret".to_string(),
+            Attributes::new()
+                .with("name", "db.password")
+                .unwrap()
+                .with("context.env", "prod")
+                .unwrap()
+        ),
+        Segment::Text("\nb: ".to_string()),
+        Segment::Cipher(
+            "ZGVm".to_string(),
+            Attributes::new().with("name", "b").unwrap()
+        ),
+        Segment::Text("\nc: ".to_string()),
+        Segment::Secure("plain".to_string(), Attributes::new())
+    )
+    .iter()
+    .map(|s| Rc::new(s.clone()))
+    .collect();
+    assert_eq!(expected, answer);
+    assert_eq!(
+        combine(answer).unwrap(),
+        r#"a: <<SECURE name="db.password" context.env="prod">>secret<</SECURE>>
+b: <<CIPHER name="b">>ZGVm<</CIPHER>>
+c: <<SECURE>>plain<</SECURE>>"#
+    );
+
+    let system = crate::encryption::new_insecure_encryption().unwrap();
+    let context = EncryptionContext::new();
+    let segments = parse_source(r#"<<SECURE name="x">>def<</SECURE>>"#.to_string()).unwrap();
+    let encrypted = encrypt(segments, system.as_ref(), &context).unwrap();
+    assert_eq!(
+        combine(encrypted).unwrap(),
+        r#"<<CIPHER name="x">>ZGVm<</CIPHER>>"#
+    );
+}
+
+#[test]
+fn test_marker_attribute_errors() {
+    assert!(parse_source(r#"<<SECURE name="1" name="2">>x<</SECURE>>"#.to_string()).is_err());
+    assert!(parse_source(r#"<<SECURE>>x<</SECURE name="1">>"#.to_string()).is_err());
+    // attributes that are not understood are refused rather than ignored
+    let error = parse_source(r#"<<SECURE name="x" key="prod">>x<</SECURE>>"#.to_string());
+    assert!(
+        error
+            .unwrap_err()
+            .to_string()
+            .contains("unknown attribute key")
+    );
+    assert!(parse_source(r#"<<SECURE context.="x">>x<</SECURE>>"#.to_string()).is_err());
+    assert!(matches!(
+        Attributes::new().with("key", "prod"),
+        Err(AppError::Usage(_))
+    ));
+    // values that could not be read back are refused
+    assert!(matches!(
+        Attributes::new().with("name", "say \"hi\""),
+        Err(AppError::Usage(_))
+    ));
+    // malformed attributes are not markers at all
+    assert_eq!(
+        parse_source(r#"<<SECURE a=>>x"#.to_string()).unwrap(),
+        vector!(Rc::new(Segment::Text(r#"<<SECURE a=>>x"#.to_string())))
+    );
+}
+
+#[test]
+fn test_block_context() {
+    let system =
+        crate::encryption::create_passphrase_encryption_with_params("pw", 64, 1, 1).unwrap();
+    let base = crate::encryption::parse_context("app=billing").unwrap();
+    let attributes = Attributes::new()
+        .with("name", "db")
+        .unwrap()
+        .with("context.env", "prod")
+        .unwrap();
+    assert_eq!(
+        attributes.context(&base),
+        crate::encryption::parse_context("app=billing,env=prod").unwrap()
+    );
+
+    let source = r#"<<SECURE context.env="prod">>secret<</SECURE>>"#;
+    let segments = parse_source(source.to_string()).unwrap();
+    let encrypted = combine(encrypt(segments.clone(), system.as_ref(), &base).unwrap()).unwrap();
+    let rewound = rewind(
+        parse_source(encrypted.clone()).unwrap(),
+        system.as_ref(),
+        &base,
+    )
+    .unwrap();
+    assert_eq!(rewound, segments);
+
+    // a block moved into a different environment no longer decrypts
+    let moved = encrypted.replace("context.env=\"prod\"", "context.env=\"staging\"");
+    assert!(rewind(parse_source(moved).unwrap(), system.as_ref(), &base).is_err());
+}
+
+#[test]
+fn test_get_value() {
+    let source = r#"a: <<SECURE>>one<</SECURE>>
+b: <<CIPHER name="db.password">>dHdv<</CIPHER>>
+c: <<CIPHER name="2">>dGhyZWU=<</CIPHER>>
+d: <<CIPHER name="dup">>Zm91cg==<</CIPHER>>
+e: <<CIPHER name="dup">>Zml2ZQ==<</CIPHER>>
+f: <<CIPHER>>bm90IGJhc2U2NA<</CIPHER>>
+"#;
+    let segments = parse_source(source.to_string()).unwrap();
+    let system = crate::encryption::new_insecure_encryption().unwrap();
+    let context = EncryptionContext::new();
+    let get = |name: &str| {
+        get_value(&segments, name, system.as_ref(), &context).map(|v| String::from_utf8(v).unwrap())
+    };
+
+    assert_eq!(get("db.password").unwrap(), "two");
+    assert_eq!(get("1").unwrap(), "one");
+    // names take priority over ordinal positions
+    assert_eq!(get("2").unwrap(), "three");
+    assert_eq!(get("3").unwrap(), "three");
+    assert!(get("dup").is_err());
+    assert!(get("0").is_err());
+    assert!(get("7").is_err());
+    assert!(get("missing").is_err());
+    // only the requested block is decrypted so a broken block elsewhere does not matter
+    assert!(get("6").is_err());
+    assert_eq!(get("4").unwrap(), "four");
+}
+
+#[test]
+fn test_parse_with_spans() {
+    let source = r#"a: <<SECURE name="x">>one<</SECURE>> <<CIPHER>>two<</CIPHER>>b"#;
+    let (segments, spans) = parse_with_spans(source).unwrap();
+    assert_eq!(segments.len(), spans.len());
+    let pieces: Vec<&str> = spans.iter().map(|r| &source[r.clone()]).collect();
+    assert_eq!(
+        pieces,
+        vec![
+            "a: ",
+            r#"<<SECURE name="x">>one<</SECURE>>"#,
+            " ",
+            "<<CIPHER>>two<</CIPHER>>",
+            "b"
+        ]
+    );
+}
+
+#[test]
+fn test_set_value() {
+    let source = "a: <<SECURE   name=x>>one<</SECURE>>\nb: <<CIPHER name=\"y\"  >>dHdv<</CIPHER>>\nc: <<SECURE>>three<</SECURE>>\n";
+    let system = crate::encryption::new_insecure_encryption().unwrap();
+    let context = EncryptionContext::new();
+
+    let answer = set_value(source, "y", b"new", system.as_ref(), &context).unwrap();
+    assert_eq!(
+        answer,
+        "a: <<SECURE   name=x>>one<</SECURE>>\nb: <<CIPHER name=\"y\">>bmV3<</CIPHER>>\nc: <<SECURE>>three<</SECURE>>\n"
+    );
+
+    let answer = set_value(source, "3", b"new", system.as_ref(), &context).unwrap();
+    assert_eq!(
+        answer,
+        "a: <<SECURE   name=x>>one<</SECURE>>\nb: <<CIPHER name=\"y\"  >>dHdv<</CIPHER>>\nc: <<CIPHER>>bmV3<</CIPHER>>\n"
+    );
+
+    assert!(set_value(source, "z", b"new", system.as_ref(), &context).is_err());
+}
+
+#[test]
+fn test_editor_command() {
+    let env = |pairs: &'static [(&'static str, &'static str)]| {
+        move |name: &str| {
+            pairs
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        }
+    };
+    assert_eq!(editor_command(env(&[])).unwrap(), vec!["vi"]);
+    assert_eq!(
+        editor_command(env(&[("EDITOR", "nano"), ("VISUAL", "code --wait")])).unwrap(),
+        vec!["code", "--wait"]
+    );
+    assert_eq!(
+        editor_command(env(&[
+            ("CIPHER_EDITOR", "emacsclient -t"),
+            ("VISUAL", "code")
+        ]))
+        .unwrap(),
+        vec!["emacsclient", "-t"]
+    );
+    assert_eq!(
+        editor_command(env(&[
+            ("CIPHER_EDITOR", " "),
+            ("EDITOR", "'/opt/my editor/bin/ed' -s")
+        ]))
+        .unwrap(),
+        vec!["/opt/my editor/bin/ed", "-s"]
+    );
+    assert!(editor_command(env(&[("EDITOR", "'unbalanced")])).is_err());
+}
+
+#[test]
+fn test_keep_recovery_file() {
+    let dir = std::env::temp_dir().display().to_string();
+    let original = temp_path("yml");
+    fs::write(&original, "original").unwrap();
+    fs::set_permissions(&original, fs::Permissions::from_mode(0o644)).unwrap();
+    let temp_file = create_temp_file(&original).unwrap();
+    fs::write(&temp_file, "<<SECURE>>edited").unwrap();
+
+    let recovered = keep_recovery_file(&temp_file, &original).unwrap();
+    let contents = fs::read_to_string(&recovered).unwrap();
+    let mode = fs::metadata(&recovered).unwrap().permissions().mode();
+    let temp_exists = exists(&temp_file).unwrap();
+    fs::remove_file(&recovered).unwrap();
+    fs::remove_file(&original).unwrap();
+
+    assert_eq!(contents, "<<SECURE>>edited");
+    assert_eq!(mode & 0o777, 0o600);
+    assert!(!temp_exists);
+    assert!(recovered.starts_with(&format!("{}/_cipher_recovered_", dir)));
+    assert!(recovered.ends_with(".yml"));
+}
+
+#[test]
+fn test_parse_error_locations() {
+    assert_eq!(line_and_column("abc", 0), (1, 1));
+    assert_eq!(line_and_column("ab\ncdé\nf", 5), (2, 3));
+    assert_eq!(line_and_column("ab\ncdé\nf", 8), (3, 1));
+
+    let source = "a: 1\nb: <<SECURE>>x<</CIPHER>>\n";
+    let error = parse_source(source.to_string()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "parse error: expected /SECURE to close the block started at line 2 column 4 but found /CIPHER at line 2 column 15\nb: <<SECURE>>x<</CIPHER>>\n              ^"
+    );
+
+    let source = "a: 1\n\tb: <<SECURE>>x\nc: 2\n";
+    let error = parse_source(source.to_string()).unwrap_err();
+    assert!(matches!(
+        &error,
+        AppError::Parse { line: 2, column: 5, text, .. } if text == "\tb: <<SECURE>>x"
+    ));
+    assert_eq!(
+        error.to_string(),
+        "parse error: expected /SECURE but found end of string for the block started at line 2 column 5\n\tb: <<SECURE>>x\n\t   ^"
+    );
+
+    let source = "a: <</CIPHER>>";
+    let error = parse_source(source.to_string()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "parse error: expected start tag but found /CIPHER at line 1 column 4\na: <</CIPHER>>\n   ^"
+    );
+
+    let source = "\n\na: <<SECURE name=1 name=2>>";
+    let error = parse_source(source.to_string()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "parse error: duplicate attribute name at line 3 column 4\na: <<SECURE name=1 name=2>>\n   ^"
+    );
+}
+
+#[test]
+fn test_error_kinds() {
+    let system =
+        crate::encryption::create_passphrase_encryption_with_params("pw", 64, 1, 1).unwrap();
+    let context = EncryptionContext::new();
+    let segments = parse_source("<<SECURE>>secret<</SECURE>>".to_string()).unwrap();
+    let encrypted = encrypt(segments, system.as_ref(), &context).unwrap();
+
+    let other =
+        crate::encryption::create_passphrase_encryption_with_params("other", 64, 1, 1).unwrap();
+    let error = rewind(encrypted, other.as_ref(), &context).unwrap_err();
+    assert!(matches!(error, AppError::Tampered { .. }));
+
+    let corrupt = parse_source("<<CIPHER>>not base64!<</CIPHER>>".to_string()).unwrap();
+    let error = rewind(corrupt, system.as_ref(), &context).unwrap_err();
+    assert!(matches!(error, AppError::Tampered { .. }));
+    assert!(error.source().is_some());
+
+    let error = load_file("/no/such/file").unwrap_err();
+    assert!(matches!(error, AppError::Io { .. }));
+    let source = error.source().unwrap().downcast_ref::<std::io::Error>();
+    assert_eq!(source.unwrap().kind(), std::io::ErrorKind::NotFound);
+
+    let error = find_block(&Vector::new(), "missing").unwrap_err();
+    assert!(matches!(error, AppError::Usage(_)));
+}
+
+#[test]
+fn test_parallel_map() {
+    let items: Vec<usize> = (0..50).collect();
+    let answer = parallel_map(&items, |i| Ok(i * 2)).unwrap();
+    assert_eq!(answer, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+
+    let error = parallel_map(&items, |i| {
+        if *i == 20 {
+            Err(AppError::usage("twenty"))
+        } else {
+            Ok(*i)
+        }
+    })
+    .unwrap_err();
+    assert_eq!(error.to_string(), "usage error: twenty");
+
+    let system = crate::encryption::new_insecure_encryption().unwrap();
+    let source = (0..20)
+        .map(|i| format!("<<SECURE>>value {}<</SECURE>> and ", i))
+        .collect::<String>();
+    let segments = parse_source(source.clone()).unwrap();
+    let context = EncryptionContext::new();
+    let encrypted = encrypt(segments, system.as_ref(), &context).unwrap();
+    let rewound = rewind(encrypted, system.as_ref(), &context).unwrap();
+    assert_eq!(combine(rewound).unwrap(), source);
+}
+
+#[test]
+fn test_async_source_functions() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let source = "a=<<SECURE name=\"a\">>one<</SECURE>> b=<<SECURE>>two<</SECURE>>".to_string();
+    let (encrypted, rewound, decrypted) = runtime
+        .block_on(async {
+            // spawning requires the futures to be Send
+            tokio::spawn(async move {
+                let system = crate::encryption::BlockingAdapter(
+                    crate::encryption::create_passphrase_encryption_with_params(
+                        "correct horse",
+                        64,
+                        1,
+                        1,
+                    )
+                    .unwrap(),
+                );
+                let context = EncryptionContext::new();
+                let encrypted = encrypt_source_async(&source, &system, &context).await?;
+                let rewound = rewind_source_async(&encrypted, &system, &context).await?;
+                let decrypted = decrypt_source_async(&encrypted, &system, &context).await?;
+                Ok::<_, AppError>((encrypted, rewound, decrypted))
+            })
+            .await
+        })
+        .unwrap()
+        .unwrap();
+    assert!(encrypted.starts_with("a=<<CIPHER name=\"a\">>"));
+    assert_eq!(
+        rewound,
+        "a=<<SECURE name=\"a\">>one<</SECURE>> b=<<SECURE>>two<</SECURE>>"
+    );
+    assert_eq!(decrypted, b"a=one b=two");
+}
+
+#[test]
+fn test_binary_blocks() {
+    let system =
+        crate::encryption::create_passphrase_encryption_with_params("pw", 64, 1, 1).unwrap();
+    let context = EncryptionContext::new();
+    // the standard alphabet, padding and line breaks are all accepted
+    let source = "key: <<SECURE name=\"der\" encoding=base64>>/wCA\n/w==<</SECURE>>\n";
+    let raw: &[u8] = &[0xff, 0x00, 0x80, 0xff];
+
+    let encrypted = encrypt(
+        parse_source(source.to_string()).unwrap(),
+        system.as_ref(),
+        &context,
+    )
+    .unwrap();
+    let contents = combine(encrypted.clone()).unwrap();
+    assert!(contents.contains(" encoding=\"base64\">>"));
+
+    let rewound = rewind(encrypted.clone(), system.as_ref(), &context).unwrap();
+    assert_eq!(
+        combine(rewound).unwrap(),
+        "key: <<SECURE name=\"der\" encoding=\"base64\">>/wCA/w==<</SECURE>>\n"
+    );
+    let decrypted = decrypt(encrypted.clone(), system.as_ref(), &context).unwrap();
+    assert_eq!(expand(decrypted).unwrap(), [b"key: ", raw, b"\n"].concat());
+    assert_eq!(
+        get_value(&encrypted, "der", system.as_ref(), &context).unwrap(),
+        raw
+    );
+
+    // set stores binary values exactly, including a trailing newline
+    let answer = set_value(&contents, "der", b"\x01\n", system.as_ref(), &context).unwrap();
+    let segments = parse_source(answer).unwrap();
+    assert_eq!(
+        get_value(&segments, "der", system.as_ref(), &context).unwrap(),
+        b"\x01\n"
+    );
+
+    let error = parse_source("<<SECURE encoding=hex>>00<</SECURE>>".to_string())
+        .and_then(|segments| encrypt(segments, system.as_ref(), &context))
+        .unwrap_err();
+    assert!(matches!(error, AppError::Usage(_)));
+    let error = parse_source("<<SECURE encoding=base64>>not base64!<</SECURE>>".to_string())
+        .and_then(|segments| encrypt(segments, system.as_ref(), &context))
+        .unwrap_err();
+    assert!(matches!(error, AppError::Usage(_)));
+}
+
+#[test]
+fn test_errors_name_the_block() {
+    let system =
+        crate::encryption::with_envelope(crate::encryption::new_insecure_encryption().unwrap());
+    let context = EncryptionContext::new();
+    let source = "<<SECURE>>a<</SECURE>> <<CIPHER name=\"db\">>~1~kms:alias/prod~abc<</CIPHER>>";
+    let error = rewind(
+        parse_source(source.to_string()).unwrap(),
+        system.as_ref(),
+        &context,
+    )
+    .unwrap_err();
+    assert!(matches!(error, AppError::UnknownKey { .. }));
+    assert_eq!(
+        error.to_string(),
+        "unknown key: block 2 (db): decrypt: encrypted with key kms:alias/prod which is not configured"
+    );
+}
+
+#[test]
+fn test_rotate() {
+    let from = encryption::with_envelope(encryption::new_insecure_encryption().unwrap());
+    let to = encryption::with_envelope(key_file_system());
+    let context = EncryptionContext::new();
+    let current = to.encrypt("ghi", &context).unwrap();
+    // an enveloped block, a binary block without an envelope, a block already
+    // under the target key (which `from` cannot decrypt) and a plaintext block
+    let source = format!(
+        "<<CIPHER name=\"a\">>~1~debug:~ZGVm<</CIPHER>> \
+         <<CIPHER encoding=base64>>_wCA_w==<</CIPHER>> \
+         <<CIPHER>>{}<</CIPHER>> <<SECURE>>jkl<</SECURE>>",
+        current
+    );
+
+    let rotated = rotate(
+        parse_source(source).unwrap(),
+        from.as_ref(),
+        to.as_ref(),
+        &context,
+    )
+    .unwrap();
+    let ciphers: Vec<&String> = rotated
+        .iter()
+        .filter_map(|seg| cipher_block(seg).map(|(text, _)| text))
+        .collect();
+    assert_eq!(ciphers.len(), 3);
+    assert!(ciphers.iter().all(|c| c.starts_with("~1~file:sha256:")));
+    assert_eq!(*ciphers[2], current);
+    assert_eq!(
+        get_value(&rotated, "a", to.as_ref(), &context).unwrap(),
+        b"def"
+    );
+    let decrypted = decrypt(rotated, to.as_ref(), &context).unwrap();
+    assert_eq!(
+        expand(decrypted).unwrap(),
+        [b"def ", &[0xff, 0x00, 0x80, 0xff][..], b" ghi jkl"].concat()
+    );
+}
+
+#[test]
+fn test_rotate_passphrase() {
+    // both systems have the key id pass: but the blocks still need re-encrypting
+    let old = encryption::with_envelope(
+        encryption::create_passphrase_encryption_with_params("old", 64, 1, 1).unwrap(),
+    );
+    let new = encryption::with_envelope(
+        encryption::create_passphrase_encryption_with_params("new", 64, 1, 1).unwrap(),
+    );
+    let context = EncryptionContext::new();
+    let source = format!(
+        "<<CIPHER name=\"a\">>{}<</CIPHER>>",
+        old.encrypt("secret", &context).unwrap()
+    );
+
+    let rotated = rotate(
+        parse_source(source).unwrap(),
+        old.as_ref(),
+        new.as_ref(),
+        &context,
+    )
+    .unwrap();
+    assert_eq!(
+        get_value(&rotated, "a", new.as_ref(), &context).unwrap(),
+        b"secret"
+    );
+    assert!(matches!(
+        get_value(&rotated, "a", old.as_ref(), &context),
+        Err(AppError::Tampered { .. })
+    ));
 }
@@ -1,60 +1,276 @@
 #[cfg(test)]
 mod tests;
 
+#[cfg(feature = "age")]
+mod age_format;
+mod composite;
+mod data_key;
+mod envelope;
+mod key_file;
+#[cfg(feature = "aws-kms")]
+mod kms;
+#[cfg(feature = "openpgp")]
+mod openpgp;
+mod passphrase;
+#[cfg(feature = "aws-kms")]
+mod raw_keyring;
+mod registry;
+
+#[cfg(feature = "age")]
+pub use age_format::{
+    create_age_encryption, create_age_passphrase_encryption,
+    create_age_passphrase_encryption_with_work_factor,
+};
+pub use composite::create_composite_encryption;
+pub use data_key::with_data_key_reuse;
+pub use envelope::{parse_envelope, with_envelope, with_envelope_async};
+pub use key_file::{create_key_file_encryption, generate_key_file};
+#[cfg(feature = "aws-kms")]
+pub use kms::{
+    create_kms_discovery_encryption, create_kms_discovery_encryption_async, create_kms_encryption,
+    create_kms_encryption_async,
+};
+#[cfg(feature = "openpgp")]
+pub use openpgp::create_openpgp_encryption;
+pub use passphrase::{
+    create_passphrase_encryption, create_passphrase_encryption_with_params, prompt_new_passphrase,
+    prompt_passphrase, read_passphrase_fd,
+};
+pub use registry::{
+    BackendFactory, BackendOptions, PassphraseSource, backend_schemes, create_encryption,
+    register_backend,
+};
+
 use crate::app::AppError;
-use aws_esdk;
-use aws_esdk::client as esdk_client;
-use aws_esdk::error::BuildError;
-use aws_esdk::material_providers::client as mpl_client;
-use aws_esdk::material_providers::types::keyring::KeyringRef;
-use aws_esdk::material_providers::types::material_providers_config::MaterialProvidersConfig;
-use aws_esdk::types::aws_encryption_sdk_config::AwsEncryptionSdkConfig;
+use async_trait::async_trait;
 use base64::{DecodeError, Engine as _, engine::general_purpose::URL_SAFE};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::ops::Deref;
 
 impl From<DecodeError> for AppError {
     fn from(error: DecodeError) -> Self {
-        AppError::from_error("base64 decode error", &error)
+        AppError::Tampered {
+            context: "base64 decode error".to_string(),
+            detail: error.to_string(),
+            source: Some(Box::new(error)),
+        }
     }
 }
 
-impl From<BuildError> for AppError {
-    fn from(error: BuildError) -> Self {
-        AppError::from_error("aws build error", &error)
+/// Non-secret key/value pairs that are cryptographically bound to a ciphertext.
+/// Decryption fails unless the same context is provided that was used to encrypt.
+pub type EncryptionContext = BTreeMap<String, String>;
+
+/// Trait for structs that can encrypt and decrypt bytes and strings.
+/// Not all implementations are secure.  Be sure to check the doc comments
+/// for the function that creates them for details on the algorithm used.
+/// Encrypted strings are expected to be base64 encoded but can use other
+/// encodings as long as a round trip is possible between `encrypt_bytes()`
+/// and `decrypt_bytes()`.
+pub trait EncryptionSystem: Send + Sync {
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError>;
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError>;
+
+    fn encrypt(&self, plaintext: &str, context: &EncryptionContext) -> Result<String, AppError> {
+        self.encrypt_bytes(plaintext.as_bytes(), context)
+    }
+
+    /// Fails if the plaintext is not valid UTF-8.
+    fn decrypt(&self, ciphertext: &str, context: &EncryptionContext) -> Result<String, AppError> {
+        Ok(String::from_utf8(self.decrypt_bytes(ciphertext, context)?)?)
     }
-}
 
-impl From<aws_esdk::types::error::Error> for AppError {
-    fn from(error: aws_esdk::types::error::Error) -> Self {
-        AppError::from_error("aws sdk error", &error)
+    /// Identifies the key used to encrypt, such as a KMS key ARN or a key file
+    /// fingerprint, without revealing anything secret.  It is stored in the
+    /// envelope of each value (see `with_envelope()`) so the right key can be
+    /// found when decrypting.  Systems without one write values with no envelope.
+    fn key_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns true if values whose envelope names `key_id` can be decrypted by
+    /// this system.  By default that is when it shares a key with `key_id()`
+    /// but systems such as KMS discovery accept any key that passes a filter.
+    fn can_decrypt(&self, key_id: &str) -> bool {
+        self.key_id()
+            .is_some_and(|own| envelope::key_ids_match(key_id, &own))
     }
 }
 
-impl From<aws_esdk::material_providers::types::error::Error> for AppError {
-    fn from(error: aws_esdk::material_providers::types::error::Error) -> Self {
-        AppError::from_error("aws mat prov error", &error)
+/// Async companion to `EncryptionSystem` for callers that are already running
+/// inside an async runtime.  The KMS systems (see `create_kms_encryption_async()`)
+/// implement it by awaiting their requests, and any other system can be used
+/// through `BlockingAdapter`.
+#[async_trait]
+pub trait AsyncEncryptionSystem: Send + Sync {
+    async fn encrypt_bytes_async(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError>;
+    async fn decrypt_bytes_async(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError>;
+
+    async fn encrypt_async(
+        &self,
+        plaintext: &str,
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        self.encrypt_bytes_async(plaintext.as_bytes(), context)
+            .await
+    }
+
+    /// Fails if the plaintext is not valid UTF-8.
+    async fn decrypt_async(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        Ok(String::from_utf8(
+            self.decrypt_bytes_async(ciphertext, context).await?,
+        )?)
     }
 }
 
-/// Trait for structs that can encrypt and decrypt strings.
-/// Not all implementations are secure.  Be sure to check the doc comments
-/// for the function that creates them for details on the algorithm used.
-/// Encrypted strings are expected to be base64 encoded but can use other
-/// encodings as long as a round trip is possible between `encrypt()` and `decrypt().
-pub trait EncryptionSystem {
-    fn encrypt(&self, plaintext: &str) -> Result<String, AppError>;
-    fn decrypt(&self, ciphertext: &str) -> Result<String, AppError>;
+/// Uses a blocking `EncryptionSystem` (held in a `Box`, `Arc` or reference) as
+/// an `AsyncEncryptionSystem` by calling its methods directly.  The calling
+/// task is blocked while they run, which is fine for local systems such as key
+/// files and passphrases but not for systems that wait on the network.
+pub struct BlockingAdapter<S>(pub S);
+
+#[async_trait]
+impl<S> AsyncEncryptionSystem for BlockingAdapter<S>
+where
+    S: Deref + Send + Sync,
+    S::Target: EncryptionSystem,
+{
+    async fn encrypt_bytes_async(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        self.0.encrypt_bytes(plaintext, context)
+    }
+
+    async fn decrypt_bytes_async(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        self.0.decrypt_bytes(ciphertext, context)
+    }
 }
 
 struct InsecureEncryptionSystem;
 
 impl EncryptionSystem for InsecureEncryptionSystem {
-    fn encrypt(&self, plaintext: &str) -> Result<String, AppError> {
-        base64_encode(&plaintext)
+    fn key_id(&self) -> Option<String> {
+        Some("debug:".to_string())
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        _context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        Ok(URL_SAFE.encode(plaintext))
+    }
+
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        _context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        Ok(URL_SAFE.decode(ciphertext.as_bytes())?)
+    }
+}
+
+/// Parse an encryption context from a string of comma separated `key=value` pairs
+/// such as `env=prod,app=billing`.  Whitespace around keys and values is ignored.
+///
+/// ```
+/// let context = cipher::encryption::parse_context("env=prod, app=billing").unwrap();
+/// assert_eq!(context.get("env"), Some(&"prod".to_string()));
+/// assert_eq!(context.get("app"), Some(&"billing".to_string()));
+/// ```
+pub fn parse_context(source: &str) -> Result<EncryptionContext, AppError> {
+    let mut context = EncryptionContext::new();
+    for pair in source.split(',').filter(|p| !p.trim().is_empty()) {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                context.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            _ => {
+                return Err(AppError::usage(
+                    format!(
+                        "expected key=value in encryption context but found {}",
+                        pair
+                    )
+                    .as_str(),
+                ));
+            }
+        }
     }
+    Ok(context)
+}
+
+/// Separates the key URIs in a list of keys such as `CIPHER_KEYS`.  Commas
+/// cannot be used since key URIs such as `kms-discovery:` contain them.
+pub const KEY_LIST_SEPARATOR: char = ';';
 
-    fn decrypt(&self, ciphertext: &str) -> Result<String, AppError> {
-        base64_decode(ciphertext)
+/// Parse a list of key URIs separated by `;`, ignoring whitespace around each
+/// key and empty entries.
+///
+/// ```
+/// let keys = cipher::encryption::parse_key_list("file:/k; kms-discovery:aws:1111,2222;");
+/// assert_eq!(keys, ["file:/k", "kms-discovery:aws:1111,2222"]);
+/// ```
+pub fn parse_key_list(source: &str) -> Vec<String> {
+    source
+        .split(KEY_LIST_SEPARATOR)
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Serialize a context into bytes suitable for use as AEAD associated data.
+/// Each key and value is preceded by its length so the encoding is unambiguous.
+/// An empty context produces no bytes which keeps values encrypted without
+/// a context decryptable.
+pub(crate) fn context_aad(context: &EncryptionContext) -> Vec<u8> {
+    let mut answer = Vec::new();
+    for (key, value) in context {
+        for s in [key, value] {
+            answer.extend_from_slice(&(s.len() as u32).to_be_bytes());
+            answer.extend_from_slice(s.as_bytes());
+        }
+    }
+    answer
+}
+
+/// Fails when `context` is not empty, for formats such as `format` that have no
+/// associated data to bind a context to.  Ignoring the context instead would let
+/// a block decrypt under a different context than the one it was written with.
+#[cfg(any(feature = "age", feature = "openpgp"))]
+pub(crate) fn reject_context(format: &str, context: &EncryptionContext) -> Result<(), AppError> {
+    if context.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::usage(
+            format!("{} values cannot bind an encryption context", format).as_str(),
+        ))
     }
 }
 
@@ -85,97 +301,3 @@ pub fn base64_decode(source: &str) -> Result<String, AppError> {
 pub fn new_insecure_encryption() -> Result<Box<dyn EncryptionSystem>, AppError> {
     Ok(Box::new(InsecureEncryptionSystem))
 }
-
-struct AwsEncryptionSystem {
-    esdk_client: esdk_client::Client,
-    kms_keyring: KeyringRef,
-}
-
-impl EncryptionSystem for AwsEncryptionSystem {
-    fn encrypt(&self, plaintext: &str) -> Result<String, AppError> {
-        let encryption_response = trpl::run(async {
-            self.esdk_client
-                .encrypt()
-                .plaintext(plaintext.as_bytes())
-                .keyring(self.kms_keyring.clone())
-                .encryption_context(HashMap::new())
-                .send()
-                .await
-        })?;
-
-        let ciphertext_bytes = encryption_response
-            .ciphertext
-            .ok_or_else(|| {
-                AppError::from_str(
-                    "aws encrypt",
-                    "Unable to unwrap ciphertext from encryption response",
-                )
-            })?
-            .into_inner();
-
-        Ok(URL_SAFE.encode(ciphertext_bytes.as_slice()))
-    }
-
-    fn decrypt(&self, base64_ciphertext: &str) -> Result<String, AppError> {
-        let ciphertext_bytes = URL_SAFE.decode(base64_ciphertext.as_bytes())?;
-        let decryption_response = trpl::run(async {
-            self.esdk_client
-                .decrypt()
-                .ciphertext(ciphertext_bytes)
-                .keyring(self.kms_keyring.clone())
-                .encryption_context(HashMap::new())
-                .send()
-                .await
-        })?;
-
-        let decrypted_plaintext = decryption_response
-            .plaintext
-            .ok_or_else(|| {
-                AppError::from_str(
-                    "aws decrypt",
-                    "Unable to unwrap plaintext from decryption response",
-                )
-            })?
-            .into_inner();
-
-        let s = String::from_utf8(decrypted_plaintext)?;
-        Ok(s)
-    }
-}
-
-async fn create_kms_encryption_async(
-    key_id: &str,
-    base_url: &Option<String>,
-) -> Result<Box<dyn EncryptionSystem>, AppError> {
-    let mut sdk_config_loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
-    if let Some(base_url) = base_url {
-        sdk_config_loader = sdk_config_loader.endpoint_url(base_url);
-    }
-    let sdk_config = sdk_config_loader.load().await;
-    let kms_client = aws_sdk_kms::Client::new(&sdk_config);
-
-    let mpl_config = MaterialProvidersConfig::builder().build()?;
-    let mpl = mpl_client::Client::from_conf(mpl_config)?;
-
-    let kms_keyring = mpl
-        .create_aws_kms_keyring()
-        .kms_client(kms_client.clone())
-        .kms_key_id(key_id)
-        .send()
-        .await?;
-
-    let esdk_config = AwsEncryptionSdkConfig::builder().build()?;
-    let esdk_client = esdk_client::Client::from_conf(esdk_config)?;
-
-    Ok(Box::new(AwsEncryptionSystem {
-        esdk_client,
-        kms_keyring,
-    }))
-}
-
-pub fn create_kms_encryption(
-    key_id: &str,
-    base_url: &Option<String>,
-) -> Result<Box<dyn EncryptionSystem>, AppError> {
-    trpl::run(async { create_kms_encryption_async(key_id, base_url).await })
-}
@@ -0,0 +1,258 @@
+use crate::app::AppError;
+use crate::encryption::envelope::{KEY_SEPARATOR, key_ids_match};
+use crate::encryption::{EncryptionContext, EncryptionSystem, reject_context};
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::SecretString;
+use age::{DecryptError, Decryptor, Encryptor, scrypt, x25519};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use std::fs::read_to_string;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Key id prefix for X25519 recipients, followed by the recipient (`age1...`).
+const RECIPIENT_PREFIX: &str = "age:";
+
+/// Key id of values encrypted with a passphrase.
+const SCRYPT_KEY_ID: &str = "age-scrypt:";
+
+/// The line that starts an ASCII armored age file.
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// How values are encrypted: to X25519 recipients (decrypting with matching
+/// identities) or with a passphrase.
+enum AgeKeys {
+    X25519 {
+        recipients: Vec<x25519::Recipient>,
+        identities: Vec<x25519::Identity>,
+    },
+    Scrypt {
+        passphrase: String,
+        work_factor: Option<u8>,
+    },
+}
+
+/// Encrypts values in the age format so that they can also be decrypted with
+/// the `age` and `rage` tools, e.g. `base64 -d | age -d -i key.txt`.  Values are
+/// base64 encoded age files, or ASCII armored age files when `armor` is set.
+///
+/// The age format has no associated data so values cannot be bound to an
+/// encryption context, and a non-empty context is refused (see `reject_context()`).
+struct AgeEncryptionSystem {
+    keys: AgeKeys,
+    armor: bool,
+}
+
+fn age_error(context: &str, error: impl std::fmt::Display) -> AppError {
+    AppError::from_str(context, error.to_string().as_str())
+}
+
+impl From<DecryptError> for AppError {
+    fn from(error: DecryptError) -> Self {
+        match error {
+            DecryptError::DecryptionFailed
+            | DecryptError::InvalidMac
+            | DecryptError::NoMatchingKeys => AppError::tampered(
+                "age decrypt",
+                "unable to decrypt (wrong identity or passphrase, or tampered data)",
+            ),
+            error => age_error("age decrypt", error),
+        }
+    }
+}
+
+impl AgeEncryptionSystem {
+    fn encryptor(&self) -> Result<Encryptor, AppError> {
+        match &self.keys {
+            AgeKeys::X25519 { recipients, .. } => {
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(|e| age_error("age encrypt", e))
+            }
+            AgeKeys::Scrypt {
+                passphrase,
+                work_factor,
+            } => {
+                let mut recipient = scrypt::Recipient::new(SecretString::from(passphrase.clone()));
+                if let Some(log_n) = work_factor {
+                    recipient.set_work_factor(*log_n);
+                }
+                Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                    .map_err(|e| age_error("age encrypt", e))
+            }
+        }
+    }
+}
+
+impl EncryptionSystem for AgeEncryptionSystem {
+    /// The recipients, so that blocks show who can decrypt them.
+    fn key_id(&self) -> Option<String> {
+        match &self.keys {
+            AgeKeys::X25519 { recipients, .. } => {
+                let ids: Vec<String> = recipients
+                    .iter()
+                    .map(|r| format!("{}{}", RECIPIENT_PREFIX, r))
+                    .collect();
+                Some(ids.join(&KEY_SEPARATOR.to_string()))
+            }
+            AgeKeys::Scrypt { .. } => Some(SCRYPT_KEY_ID.to_string()),
+        }
+    }
+
+    /// Any value encrypted to one of our identities can be decrypted, even when
+    /// the identity is not in the recipients file.  Without identities values
+    /// for our recipients are accepted so that decrypting explains what is missing.
+    fn can_decrypt(&self, key_id: &str) -> bool {
+        match &self.keys {
+            AgeKeys::X25519 { identities, .. } if !identities.is_empty() => {
+                identities.iter().any(|identity| {
+                    let own = format!("{}{}", RECIPIENT_PREFIX, identity.to_public());
+                    key_id.split(KEY_SEPARATOR).any(|id| id == own)
+                })
+            }
+            _ => self.key_id().is_some_and(|own| key_ids_match(key_id, &own)),
+        }
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        reject_context("age", context)?;
+        let format = if self.armor {
+            Format::AsciiArmor
+        } else {
+            Format::Binary
+        };
+        let mut output = Vec::new();
+        let armored = ArmoredWriter::wrap_output(&mut output, format)?;
+        let mut writer = self.encryptor()?.wrap_output(armored)?;
+        writer.write_all(plaintext)?;
+        writer.finish()?.finish()?;
+        if self.armor {
+            Ok(String::from_utf8(output)?)
+        } else {
+            Ok(STANDARD.encode(output))
+        }
+    }
+
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        reject_context("age", context)?;
+        // armored values are read as they are and the reader removes the armor
+        let bytes = if ciphertext.trim_start().starts_with(ARMOR_BEGIN) {
+            ciphertext.trim().as_bytes().to_vec()
+        } else {
+            let compact: String = ciphertext.chars().filter(|c| !c.is_whitespace()).collect();
+            STANDARD
+                .decode(compact)
+                .map_err(|e| AppError::tampered("age decrypt", e.to_string().as_str()))?
+        };
+        let decryptor = Decryptor::new(ArmoredReader::new(bytes.as_slice()))?;
+        let mut reader = match &self.keys {
+            AgeKeys::X25519 { identities, .. } => {
+                if identities.is_empty() {
+                    return Err(AppError::usage(
+                        "no age identity provided to decrypt with (see CIPHER_AGE_IDENTITY)",
+                    ));
+                }
+                decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))?
+            }
+            AgeKeys::Scrypt { passphrase, .. } => {
+                let identity = scrypt::Identity::new(SecretString::from(passphrase.clone()));
+                decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))?
+            }
+        };
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+}
+
+/// Reads the non-empty lines of `path` that are not `#` comments, parsing each
+/// with `parse`.
+fn read_key_lines<T>(
+    path: &str,
+    what: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, AppError> {
+    let contents = read_to_string(path)?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            parse(line).map_err(|e| {
+                AppError::usage(
+                    format!("invalid {} on line {} of {}: {}", what, index + 1, path, e).as_str(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Create an `EncryptionSystem` that encrypts values in the age format to the
+/// X25519 recipients (`age1...`) listed in the file at `recipients_path`, one
+/// per line with `#` comments, which is the format of `age -R`.  Values are
+/// decrypted with the identities (`AGE-SECRET-KEY-1...`) in the file at
+/// `identity_path`, as written by `age-keygen`.  ASCII armored values are
+/// written when `armor` is set.
+pub fn create_age_encryption(
+    recipients_path: &str,
+    identity_path: Option<&str>,
+    armor: bool,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let recipients = read_key_lines(recipients_path, "age recipient", |line| {
+        x25519::Recipient::from_str(line).map_err(|e| e.to_string())
+    })?;
+    if recipients.is_empty() {
+        return Err(AppError::usage(
+            format!("no age recipients found in {}", recipients_path).as_str(),
+        ));
+    }
+    let identities = match identity_path {
+        Some(path) => read_key_lines(path, "age identity", |line| {
+            x25519::Identity::from_str(line).map_err(|e| e.to_string())
+        })?,
+        None => Vec::new(),
+    };
+    Ok(Box::new(AgeEncryptionSystem {
+        keys: AgeKeys::X25519 {
+            recipients,
+            identities,
+        },
+        armor,
+    }))
+}
+
+/// Create an `EncryptionSystem` that encrypts values in the age format with a
+/// passphrase, as `age -p` does.  The scrypt work factor is chosen by age to
+/// take about a second for each value.
+pub fn create_age_passphrase_encryption(
+    passphrase: &str,
+    armor: bool,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    create_age_passphrase_encryption_with_work_factor(passphrase, None, armor)
+}
+
+/// Same as `create_age_passphrase_encryption()` but with the scrypt work factor
+/// (`N = 2^log_n`) given explicitly.  Mainly useful for tests.
+pub fn create_age_passphrase_encryption_with_work_factor(
+    passphrase: &str,
+    log_n: Option<u8>,
+    armor: bool,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    if log_n.is_some_and(|log_n| log_n == 0 || log_n >= 64) {
+        return Err(AppError::usage("invalid scrypt work factor"));
+    }
+    Ok(Box::new(AgeEncryptionSystem {
+        keys: AgeKeys::Scrypt {
+            passphrase: passphrase.to_string(),
+            work_factor: log_n,
+        },
+        armor,
+    }))
+}
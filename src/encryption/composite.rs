@@ -0,0 +1,125 @@
+use crate::app::AppError;
+use crate::encryption::envelope::key_ids_match;
+use crate::encryption::registry::{canonical_key, could_decrypt};
+use crate::encryption::{
+    BackendOptions, EncryptionContext, EncryptionSystem, create_encryption, parse_envelope,
+};
+use std::sync::{Arc, Mutex};
+
+/// A backend that has been created.
+type Created = Arc<dyn EncryptionSystem>;
+
+/// Routes each value to the backend for the key named in its envelope (see
+/// `with_envelope()`) so that one file can mix blocks encrypted with different
+/// keys.  Values are encrypted with the first key, which is also used for values
+/// written without an envelope.
+///
+/// Backends are only created when a value needs them.  A value's key id is first
+/// compared with the configured key URIs, which works for keys such as KMS ARNs
+/// whose key id is their URI.  Otherwise the remaining backends whose scheme
+/// matches the key id (such as `file:` for `file:sha256:...`) are created in
+/// order until one can decrypt it (see `EncryptionSystem::can_decrypt()`), as
+/// needed for key files.  Backends that fail to be created are skipped.
+struct CompositeEncryptionSystem {
+    keys: Vec<String>,
+    options: BackendOptions,
+    systems: Mutex<Vec<Option<Created>>>,
+}
+
+impl CompositeEncryptionSystem {
+    /// Returns the backend for `keys[index]`, creating it if needed.  The lock
+    /// is held while creating so that concurrent blocks never create the same
+    /// backend twice (and never prompt twice for a passphrase).
+    fn system_at(
+        &self,
+        systems: &mut [Option<Created>],
+        index: usize,
+    ) -> Result<Created, AppError> {
+        if systems[index].is_none() {
+            systems[index] = Some(Arc::from(create_encryption(
+                &self.keys[index],
+                &self.options,
+            )?));
+        }
+        Ok(systems[index].clone().unwrap())
+    }
+
+    fn primary(&self) -> Result<Arc<dyn EncryptionSystem>, AppError> {
+        let mut systems = self.systems.lock().unwrap();
+        self.system_at(&mut systems, 0)
+    }
+
+    fn find(&self, key_id: &str) -> Result<Arc<dyn EncryptionSystem>, AppError> {
+        let mut systems = self.systems.lock().unwrap();
+        let created = systems
+            .iter()
+            .flatten()
+            .find(|system| system.can_decrypt(key_id));
+        if let Some(system) = created {
+            return Ok(system.clone());
+        }
+        if let Some(index) = self
+            .keys
+            .iter()
+            .position(|key| key_ids_match(key_id, &canonical_key(key)))
+        {
+            return self.system_at(&mut systems, index);
+        }
+        // a backend that cannot be created may still be the one that was needed
+        let mut failure = None;
+        for index in 0..self.keys.len() {
+            if systems[index].is_some() || !could_decrypt(&self.keys[index], key_id) {
+                continue;
+            }
+            match self.system_at(&mut systems, index) {
+                Ok(system) if system.can_decrypt(key_id) => return Ok(system),
+                Ok(_) => {}
+                Err(error) => failure = Some(error),
+            }
+        }
+        Err(failure.unwrap_or_else(|| AppError::unknown_key("decrypt", key_id)))
+    }
+}
+
+impl EncryptionSystem for CompositeEncryptionSystem {
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        self.primary()?.encrypt_bytes(plaintext, context)
+    }
+
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let system = match parse_envelope(ciphertext)? {
+            Some((key_id, _)) => self.find(key_id)?,
+            None => self.primary()?,
+        };
+        system.decrypt_bytes(ciphertext, context)
+    }
+
+    fn key_id(&self) -> Option<String> {
+        self.primary().ok().and_then(|system| system.key_id())
+    }
+}
+
+/// Create an encryption system that encrypts with the first of `keys` and can
+/// decrypt values encrypted with any of them.  Each key is a key URI for
+/// `create_encryption()` and its backend is only created when first needed.
+pub fn create_composite_encryption(
+    keys: &[String],
+    options: &BackendOptions,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    if keys.is_empty() {
+        return Err(AppError::usage("no keys provided"));
+    }
+    Ok(Box::new(CompositeEncryptionSystem {
+        keys: keys.to_vec(),
+        options: options.clone(),
+        systems: Mutex::new(vec![None; keys.len()]),
+    }))
+}
@@ -0,0 +1,260 @@
+use crate::app::AppError;
+use crate::encryption::{AsyncEncryptionSystem, EncryptionContext, EncryptionSystem, context_aad};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Values encrypted with a shared data key start with this prefix.  The `:` is
+/// not part of the base64 alphabet so these values can never be confused with
+/// the ones produced by the wrapped system.
+pub(crate) const DATA_KEY_PREFIX: &str = "dk1:";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const LENGTH_LEN: usize = 4;
+
+/// Encrypts every value with one random AES-256-GCM data key per encryption
+/// context instead of asking the wrapped system to encrypt each value.  The
+/// data key is encrypted ("wrapped") once by the wrapped system and the wrapped
+/// key is stored with each value so values remain independent of each other.
+///
+/// Payloads are `DATA_KEY_PREFIX` followed by the base64 encoding of the length
+/// of the wrapped key (big endian u32), the wrapped key, the nonce and the GCM
+/// ciphertext.  The wrapped key and encryption context are the associated data.
+pub(crate) struct DataKeyEncryptionSystem<S: ?Sized = dyn EncryptionSystem> {
+    inner: Box<S>,
+    reuse: bool,
+    encrypt_keys: Mutex<HashMap<EncryptionContext, (String, Aes256Gcm)>>,
+    decrypt_keys: Mutex<HashMap<(String, EncryptionContext), Aes256Gcm>>,
+}
+
+/// Decodes a value without its `DATA_KEY_PREFIX` into the wrapped key, the
+/// nonce and the GCM ciphertext.
+fn split_payload(encoded: &str) -> Result<(String, Vec<u8>, Vec<u8>), AppError> {
+    let bytes = URL_SAFE.decode(encoded.as_bytes())?;
+    let too_short = || AppError::tampered("data key decrypt", "ciphertext is too short");
+    if bytes.len() < LENGTH_LEN {
+        return Err(too_short());
+    }
+    let (length, rest) = bytes.split_at(LENGTH_LEN);
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    if rest.len() < length + NONCE_LEN {
+        return Err(too_short());
+    }
+    let (wrapped, rest) = rest.split_at(length);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    Ok((
+        String::from_utf8(wrapped.to_vec())?,
+        nonce.to_vec(),
+        ciphertext.to_vec(),
+    ))
+}
+
+/// Encrypts `plaintext` with a data key, returning the complete value.
+fn seal(
+    wrapped: &str,
+    cipher: &Aes256Gcm,
+    plaintext: &[u8],
+    context: &EncryptionContext,
+) -> Result<String, AppError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let aad = [wrapped.as_bytes(), &context_aad(context)].concat();
+    let payload = Payload {
+        msg: plaintext,
+        aad: &aad,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| AppError::from_str("data key encrypt", "unable to encrypt plaintext"))?;
+
+    let mut answer = (wrapped.len() as u32).to_be_bytes().to_vec();
+    answer.extend_from_slice(wrapped.as_bytes());
+    answer.extend_from_slice(&nonce);
+    answer.extend_from_slice(&ciphertext);
+    Ok(format!("{}{}", DATA_KEY_PREFIX, URL_SAFE.encode(answer)))
+}
+
+/// Decrypts the parts of a value from `split_payload()` with its data key.
+fn open(
+    cipher: &Aes256Gcm,
+    wrapped: &str,
+    nonce: &[u8],
+    ciphertext: &[u8],
+    context: &EncryptionContext,
+) -> Result<Vec<u8>, AppError> {
+    let aad = [wrapped.as_bytes(), &context_aad(context)].concat();
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &aad,
+    };
+    cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| {
+            AppError::tampered(
+                "data key decrypt",
+                "ciphertext failed authentication (wrong key, wrong context or tampered data)",
+            )
+        })
+}
+
+fn data_key_cipher(key: &[u8]) -> Result<Aes256Gcm, AppError> {
+    if key.len() != KEY_LEN {
+        return Err(AppError::tampered(
+            "data key decrypt",
+            "wrapped data key has the wrong length",
+        ));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+}
+
+impl<S: EncryptionSystem + ?Sized> DataKeyEncryptionSystem<S> {
+    fn new_data_key(&self, context: &EncryptionContext) -> Result<(String, Aes256Gcm), AppError> {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let wrapped = self
+            .inner
+            .encrypt(URL_SAFE.encode(key.as_slice()).as_str(), context)?;
+        Ok((wrapped, Aes256Gcm::new(&key)))
+    }
+
+    fn unwrap_data_key(
+        &self,
+        wrapped: &str,
+        context: &EncryptionContext,
+    ) -> Result<Aes256Gcm, AppError> {
+        let key = URL_SAFE.decode(self.inner.decrypt(wrapped, context)?.as_bytes())?;
+        data_key_cipher(&key)
+    }
+}
+
+impl<S: EncryptionSystem + ?Sized> EncryptionSystem for DataKeyEncryptionSystem<S> {
+    fn key_id(&self) -> Option<String> {
+        self.inner.key_id()
+    }
+
+    fn can_decrypt(&self, key_id: &str) -> bool {
+        self.inner.can_decrypt(key_id)
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        if !self.reuse {
+            return self.inner.encrypt_bytes(plaintext, context);
+        }
+        // The lock is held while a new key is wrapped so that concurrent blocks
+        // share one data key rather than each wrapping their own.
+        let (wrapped, cipher) = {
+            let mut keys = self.encrypt_keys.lock().unwrap();
+            if !keys.contains_key(context) {
+                let entry = self.new_data_key(context)?;
+                keys.insert(context.clone(), entry);
+            }
+            keys[context].clone()
+        };
+        seal(&wrapped, &cipher, plaintext, context)
+    }
+
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let Some(encoded) = ciphertext.strip_prefix(DATA_KEY_PREFIX) else {
+            return self.inner.decrypt_bytes(ciphertext, context);
+        };
+        let (wrapped, nonce, ciphertext) = split_payload(encoded)?;
+
+        let lookup = (wrapped, context.clone());
+        let cipher = {
+            let mut keys = self.decrypt_keys.lock().unwrap();
+            if !keys.contains_key(&lookup) {
+                let cipher = self.unwrap_data_key(&lookup.0, context)?;
+                keys.insert(lookup.clone(), cipher);
+            }
+            keys[&lookup].clone()
+        };
+        open(&cipher, &lookup.0, &nonce, &ciphertext, context)
+    }
+}
+
+/// Values are encrypted by the wrapped system while values using a shared data
+/// key, such as those written with `--reuse-data-key`, can still be decrypted.
+/// Wrapped data keys are decrypted without holding the lock across the request,
+/// so concurrent blocks may each decrypt the same key once.
+#[async_trait]
+impl<S: EncryptionSystem + AsyncEncryptionSystem + ?Sized> AsyncEncryptionSystem
+    for DataKeyEncryptionSystem<S>
+{
+    async fn encrypt_bytes_async(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        self.inner.encrypt_bytes_async(plaintext, context).await
+    }
+
+    async fn decrypt_bytes_async(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let Some(encoded) = ciphertext.strip_prefix(DATA_KEY_PREFIX) else {
+            return self.inner.decrypt_bytes_async(ciphertext, context).await;
+        };
+        let (wrapped, nonce, ciphertext) = split_payload(encoded)?;
+
+        let lookup = (wrapped, context.clone());
+        let cached = self.decrypt_keys.lock().unwrap().get(&lookup).cloned();
+        let cipher = match cached {
+            Some(cipher) => cipher,
+            None => {
+                let key = self.inner.decrypt_async(&lookup.0, context).await?;
+                let cipher = data_key_cipher(&URL_SAFE.decode(key.as_bytes())?)?;
+                let mut keys = self.decrypt_keys.lock().unwrap();
+                keys.entry(lookup.clone()).or_insert(cipher).clone()
+            }
+        };
+        open(&cipher, &lookup.0, &nonce, &ciphertext, context)
+    }
+}
+
+/// Wrap `system` so that, when `reuse` is true, all values encrypted during this
+/// run with the same encryption context share one data key.  The data key is
+/// encrypted once by `system` which, for KMS, turns one request per block into
+/// one per file.  Each value still stores a copy of the wrapped key, so values
+/// are no smaller.  When `reuse` is false values are encrypted by `system` as before.
+///
+/// Either way values encrypted by `system` itself are decrypted normally, values
+/// using a shared data key can be decrypted, and each distinct wrapped data key
+/// is only decrypted once.
+pub fn with_data_key_reuse(
+    system: Box<dyn EncryptionSystem>,
+    reuse: bool,
+) -> Box<dyn EncryptionSystem> {
+    Box::new(DataKeyEncryptionSystem {
+        inner: system,
+        reuse,
+        encrypt_keys: Mutex::new(HashMap::new()),
+        decrypt_keys: Mutex::new(HashMap::new()),
+    })
+}
+
+/// Same as `with_data_key_reuse()` without reuse for systems that also
+/// implement `AsyncEncryptionSystem`, so that async callers can read values
+/// that share a data key.
+#[cfg(feature = "aws-kms")]
+pub(crate) fn with_data_key_reuse_async<S>(system: S) -> DataKeyEncryptionSystem<S>
+where
+    S: EncryptionSystem + AsyncEncryptionSystem,
+{
+    DataKeyEncryptionSystem {
+        inner: Box::new(system),
+        reuse: false,
+        encrypt_keys: Mutex::new(HashMap::new()),
+        decrypt_keys: Mutex::new(HashMap::new()),
+    }
+}
@@ -0,0 +1,149 @@
+use crate::app::AppError;
+use crate::encryption::{AsyncEncryptionSystem, EncryptionContext, EncryptionSystem};
+use async_trait::async_trait;
+
+/// Marks the start of an envelope.  `~` is not part of the base64 alphabet so
+/// enveloped values can never be confused with older values without one.
+const ENVELOPE_MARKER: char = '~';
+const ENVELOPE_VERSION: &str = "1";
+
+/// Separates the keys in a key id for values that any one of several keys can
+/// decrypt (see `create_kms_encryption()`).
+pub(crate) const KEY_SEPARATOR: char = '+';
+
+/// Returns true when a system with key id `key_id` can decrypt values whose
+/// envelope names `envelope_id`, which is when the two share at least one key.
+pub(crate) fn key_ids_match(envelope_id: &str, key_id: &str) -> bool {
+    envelope_id
+        .split(KEY_SEPARATOR)
+        .any(|id| key_id.split(KEY_SEPARATOR).any(|other| other == id))
+}
+
+/// Adds a header to every value naming the format version and the key used
+/// (see `EncryptionSystem::key_id()`): `~1~<key id>~<ciphertext>`.
+///
+/// When decrypting, values with a header for a different key fail with an
+/// `UnknownKey` error rather than an authentication error, and values without
+/// a header are passed straight to the wrapped system.  Which headers are
+/// accepted is decided by the wrapped system's `can_decrypt()`.
+struct EnvelopeEncryptionSystem<S: ?Sized = dyn EncryptionSystem> {
+    inner: Box<S>,
+    key_id: String,
+}
+
+/// Splits an enveloped value into its key id and the wrapped system's ciphertext.
+/// Returns `Ok(None)` for values written without an envelope.
+pub fn parse_envelope(ciphertext: &str) -> Result<Option<(&str, &str)>, AppError> {
+    let Some(rest) = ciphertext.strip_prefix(ENVELOPE_MARKER) else {
+        return Ok(None);
+    };
+    let invalid = || AppError::tampered("envelope", "malformed envelope header");
+    let (version, rest) = rest.split_once(ENVELOPE_MARKER).ok_or_else(invalid)?;
+    if version != ENVELOPE_VERSION {
+        return Err(AppError::from_str(
+            "envelope",
+            format!("unsupported envelope version {}", version).as_str(),
+        ));
+    }
+    // key ids may contain the marker but ciphertexts never do
+    let (key_id, payload) = rest.rsplit_once(ENVELOPE_MARKER).ok_or_else(invalid)?;
+    Ok(Some((key_id, payload)))
+}
+
+impl<S: EncryptionSystem + ?Sized> EnvelopeEncryptionSystem<S> {
+    /// Adds the header to a ciphertext from the wrapped system.
+    fn seal(&self, ciphertext: String) -> String {
+        format!(
+            "{marker}{}{marker}{}{marker}{}",
+            ENVELOPE_VERSION,
+            self.key_id,
+            ciphertext,
+            marker = ENVELOPE_MARKER
+        )
+    }
+
+    /// Returns the ciphertext to pass to the wrapped system.
+    fn open<'a>(&self, ciphertext: &'a str) -> Result<&'a str, AppError> {
+        match parse_envelope(ciphertext)? {
+            Some((key_id, payload)) if self.inner.can_decrypt(key_id) => Ok(payload),
+            Some((key_id, _)) => Err(AppError::unknown_key("decrypt", key_id)),
+            None => Ok(ciphertext),
+        }
+    }
+}
+
+impl<S: EncryptionSystem + ?Sized> EncryptionSystem for EnvelopeEncryptionSystem<S> {
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        Ok(self.seal(self.inner.encrypt_bytes(plaintext, context)?))
+    }
+
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        self.inner.decrypt_bytes(self.open(ciphertext)?, context)
+    }
+
+    fn key_id(&self) -> Option<String> {
+        Some(self.key_id.clone())
+    }
+
+    fn can_decrypt(&self, key_id: &str) -> bool {
+        self.inner.can_decrypt(key_id)
+    }
+}
+
+/// Wrap `system` so that the values it encrypts carry an envelope header naming
+/// its key.  Systems without a key id are returned unchanged.
+pub fn with_envelope(system: Box<dyn EncryptionSystem>) -> Box<dyn EncryptionSystem> {
+    match system.key_id() {
+        Some(key_id) => Box::new(EnvelopeEncryptionSystem {
+            inner: system,
+            key_id,
+        }),
+        None => system,
+    }
+}
+
+#[async_trait]
+impl<S> AsyncEncryptionSystem for EnvelopeEncryptionSystem<S>
+where
+    S: EncryptionSystem + AsyncEncryptionSystem + ?Sized,
+{
+    async fn encrypt_bytes_async(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        Ok(self.seal(self.inner.encrypt_bytes_async(plaintext, context).await?))
+    }
+
+    async fn decrypt_bytes_async(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let payload = self.open(ciphertext)?;
+        self.inner.decrypt_bytes_async(payload, context).await
+    }
+}
+
+/// Same as `with_envelope()` for systems that also implement
+/// `AsyncEncryptionSystem` natively, returning the async side.
+pub fn with_envelope_async<S>(system: S) -> Box<dyn AsyncEncryptionSystem>
+where
+    S: EncryptionSystem + AsyncEncryptionSystem + 'static,
+{
+    match system.key_id() {
+        Some(key_id) => Box::new(EnvelopeEncryptionSystem {
+            inner: Box::new(system),
+            key_id,
+        }),
+        None => Box::new(system),
+    }
+}
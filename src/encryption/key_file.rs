@@ -0,0 +1,136 @@
+use crate::app::AppError;
+use crate::encryption::{EncryptionContext, EncryptionSystem, context_aad};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+use sha2::{Digest, Sha256};
+use std::fs::{OpenOptions, read_to_string};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Number of bytes of the key's SHA-256 hash shown in its key id.
+const FINGERPRINT_LEN: usize = 8;
+
+struct KeyFileEncryptionSystem {
+    cipher: Aes256Gcm,
+    fingerprint: String,
+}
+
+impl EncryptionSystem for KeyFileEncryptionSystem {
+    /// The key's fingerprint rather than the path to the file since the same
+    /// key is often stored in different places on different machines.
+    fn key_id(&self) -> Option<String> {
+        Some(format!("file:sha256:{}", self.fingerprint))
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = context_aad(context);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| AppError::from_str("aes encrypt", "unable to encrypt plaintext"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(URL_SAFE.encode(payload))
+    }
+
+    fn decrypt_bytes(
+        &self,
+        base64_ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let payload = URL_SAFE.decode(base64_ciphertext.as_bytes())?;
+        if payload.len() < NONCE_LEN {
+            return Err(AppError::tampered("aes decrypt", "ciphertext is too short"));
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let aad = context_aad(context);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                AppError::tampered(
+                    "aes decrypt",
+                    "ciphertext failed authentication (wrong key, wrong context or tampered data)",
+                )
+            })?;
+
+        Ok(plaintext)
+    }
+}
+
+pub(super) fn read_key_file(path: &str) -> Result<Vec<u8>, AppError> {
+    let encoded = read_to_string(path)?;
+    let key = URL_SAFE
+        .decode(encoded.trim().as_bytes())
+        .map_err(|e| AppError::usage(format!("invalid key file {}: {}", path, e).as_str()))?;
+    if key.len() != KEY_LEN {
+        return Err(AppError::usage(
+            format!(
+                "expected a {} byte key in key file {} but found {} bytes",
+                KEY_LEN,
+                path,
+                key.len()
+            )
+            .as_str(),
+        ));
+    }
+    Ok(key)
+}
+
+/// Returns the start of the SHA-256 hash of `key` in hex, which identifies a key
+/// without revealing it.
+pub(super) fn fingerprint(key: &[u8]) -> String {
+    Sha256::digest(key)[..FINGERPRINT_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Create a new `EncryptionSystem` that uses AES-256-GCM with a key read from
+/// the file at `path`.  The file must contain a base64 encoded 256 bit key such
+/// as the ones written by `generate_key_file()`.
+///
+/// Each encrypted value is the base64 encoding of a random 96 bit nonce followed
+/// by the GCM ciphertext and authentication tag.  The encryption context is used
+/// as the associated data.
+pub fn create_key_file_encryption(path: &str) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let key = read_key_file(path)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let fingerprint = fingerprint(&key);
+    Ok(Box::new(KeyFileEncryptionSystem {
+        cipher,
+        fingerprint,
+    }))
+}
+
+/// Generate a new random AES-256 key and write it to a new file at `path` using
+/// permissions that only allow the owner to read or write it.  Fails if the file
+/// already exists so that an existing key can never be overwritten by accident.
+pub fn generate_key_file(path: &str) -> Result<(), AppError> {
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", URL_SAFE.encode(key.as_slice()))?;
+    Ok(())
+}
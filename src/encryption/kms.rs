@@ -0,0 +1,595 @@
+use crate::app::AppError;
+use crate::encryption::data_key::with_data_key_reuse_async;
+use crate::encryption::envelope::KEY_SEPARATOR;
+use crate::encryption::raw_keyring::{
+    RAW_AES_SCHEME, RAW_RSA_SCHEME, raw_aes_keyring, raw_rsa_keyring, read_rsa_public_key,
+};
+use crate::encryption::{
+    AsyncEncryptionSystem, EncryptionContext, EncryptionSystem, with_envelope_async,
+};
+use async_trait::async_trait;
+use aws_esdk;
+use aws_esdk::client as esdk_client;
+use aws_esdk::error::BuildError;
+use aws_esdk::material_providers::client as mpl_client;
+use aws_esdk::material_providers::types::keyring::KeyringRef;
+use aws_esdk::material_providers::types::material_providers_config::MaterialProvidersConfig;
+use aws_esdk::material_providers::types::{DiscoveryFilter, EsdkAlgorithmSuiteId};
+use aws_esdk::types::aws_encryption_sdk_config::AwsEncryptionSdkConfig;
+use aws_sdk_kms::types::EncryptionAlgorithmSpec;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::future::Future;
+use std::thread;
+use tokio::runtime::{Handle, Runtime};
+
+lazy_static! {
+    /// Runtime shared by every AWS call.  It is safe for several threads to
+    /// block on it at the same time which allows blocks to be processed concurrently.
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("unable to create tokio runtime");
+}
+
+/// Runs the future created by `make` to completion on the shared runtime.  When
+/// called from inside another runtime (for example through a `BlockingAdapter`)
+/// the future is run on a separate thread since blocking a runtime's own thread
+/// on another runtime panics.  Async callers should use the systems from
+/// `create_kms_encryption_async()` instead, which never block.
+pub(super) fn block_on<T, F>(make: impl FnOnce() -> F + Send) -> T
+where
+    T: Send,
+    F: Future<Output = T>,
+{
+    if Handle::try_current().is_ok() {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| RUNTIME.block_on(make()))
+                .join()
+                .expect("encryption thread panicked")
+        })
+    } else {
+        RUNTIME.block_on(make())
+    }
+}
+
+impl From<BuildError> for AppError {
+    fn from(error: BuildError) -> Self {
+        AppError::from_error("aws build error", error)
+    }
+}
+
+/// Fragments of AWS error messages that indicate missing or rejected credentials
+/// or a lack of permission to use a key.  A key that is disabled or pending
+/// deletion (`KMSInvalidStateException`) is not a permissions problem so it is
+/// left as an `Encryption` error.
+const ACCESS_DENIED_MARKERS: [&str; 7] = [
+    "AccessDeniedException",
+    "NotAuthorized",
+    "UnrecognizedClientException",
+    "InvalidClientTokenId",
+    "InvalidSignatureException",
+    "ExpiredToken",
+    "CredentialsNotLoaded",
+];
+
+/// Fragments of ESDK error messages that indicate a ciphertext was altered or
+/// was decrypted with the wrong key or encryption context.
+const TAMPERED_MARKERS: [&str; 6] = [
+    "gather Unspecified",
+    "Invalid signature",
+    "Commitment key does not match",
+    "Encryption context does not match",
+    "Encryption context digest does not match",
+    "Reproduced encryption context",
+];
+
+/// Fragment of the ESDK error for a message that none of the configured keys
+/// is a recipient of.
+const UNKNOWN_KEY_MARKER: &str = "No Encrypted Data Keys found to match";
+
+/// Key id reported when a message is for keys that are not configured, since
+/// the ESDK error does not say which keys those are.
+const UNKNOWN_KEY_ID: &str = "(unknown)";
+
+/// The ESDK errors wrap lower level errors (including those from KMS) in several
+/// layers so the full debug text is searched to decide what kind of error it is,
+/// while the display text is what the user sees.  The ESDK errors cannot be
+/// kept as the source since they are not `Send`.
+pub(super) fn classify_aws_error(
+    context: &str,
+    error: &(impl std::fmt::Debug + std::fmt::Display),
+) -> AppError {
+    let debug_text = format!("{:?}", error);
+    let detail = error.to_string();
+    if ACCESS_DENIED_MARKERS.iter().any(|m| debug_text.contains(m)) {
+        AppError::access_denied(context, &detail)
+    } else if debug_text.contains(UNKNOWN_KEY_MARKER) {
+        AppError::unknown_key(context, UNKNOWN_KEY_ID)
+    } else if TAMPERED_MARKERS.iter().any(|m| debug_text.contains(m)) {
+        AppError::tampered(context, &detail)
+    } else {
+        AppError::from_str(context, &detail)
+    }
+}
+
+impl From<aws_esdk::types::error::Error> for AppError {
+    fn from(error: aws_esdk::types::error::Error) -> Self {
+        classify_aws_error("aws sdk error", &error)
+    }
+}
+
+impl From<aws_esdk::material_providers::types::error::Error> for AppError {
+    fn from(error: aws_esdk::material_providers::types::error::Error) -> Self {
+        classify_aws_error("aws mat prov error", &error)
+    }
+}
+
+/// Values are standard ESDK messages encrypted with a keyring for one or more
+/// keys, each of which can decrypt them.
+struct AwsEncryptionSystem {
+    esdk_client: esdk_client::Client,
+    kms_keyring: KeyringRef,
+    key_ids: Vec<String>,
+    /// Used instead of the ESDK default when one of the keys needs it.
+    algorithm_suite: Option<EsdkAlgorithmSuiteId>,
+}
+
+/// ARNs are used as they are and key ids or aliases use the `kms:` scheme so
+/// the key id is also a key URI for the registry.
+fn kms_key_id(key_id: &str) -> String {
+    if key_id.starts_with("arn:") {
+        key_id.to_string()
+    } else {
+        format!("kms:{}", key_id)
+    }
+}
+
+impl EncryptionSystem for AwsEncryptionSystem {
+    /// With several keys their ids are joined with `+`.
+    fn key_id(&self) -> Option<String> {
+        Some(self.key_ids.join(&KEY_SEPARATOR.to_string()))
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        block_on(|| self.encrypt_bytes_async(plaintext, context))
+    }
+
+    fn decrypt_bytes(
+        &self,
+        base64_ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        block_on(|| self.decrypt_bytes_async(base64_ciphertext, context))
+    }
+}
+
+#[async_trait]
+impl AsyncEncryptionSystem for AwsEncryptionSystem {
+    async fn encrypt_bytes_async(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        let encryption_response = self
+            .esdk_client
+            .encrypt()
+            .plaintext(plaintext)
+            .set_algorithm_suite_id(self.algorithm_suite)
+            .keyring(self.kms_keyring.clone())
+            .encryption_context(HashMap::from_iter(context.clone()))
+            .send()
+            .await?;
+
+        let ciphertext_bytes = encryption_response
+            .ciphertext
+            .ok_or_else(|| {
+                AppError::from_str(
+                    "aws encrypt",
+                    "Unable to unwrap ciphertext from encryption response",
+                )
+            })?
+            .into_inner();
+
+        Ok(URL_SAFE.encode(ciphertext_bytes.as_slice()))
+    }
+
+    async fn decrypt_bytes_async(
+        &self,
+        base64_ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        esdk_decrypt(
+            &self.esdk_client,
+            &self.kms_keyring,
+            base64_ciphertext,
+            context,
+        )
+        .await
+    }
+}
+
+/// Decrypts an ESDK message with `keyring`.
+async fn esdk_decrypt(
+    esdk_client: &esdk_client::Client,
+    keyring: &KeyringRef,
+    base64_ciphertext: &str,
+    context: &EncryptionContext,
+) -> Result<Vec<u8>, AppError> {
+    let ciphertext_bytes = URL_SAFE.decode(base64_ciphertext.as_bytes())?;
+    let decryption_response = esdk_client
+        .decrypt()
+        .ciphertext(ciphertext_bytes)
+        .keyring(keyring.clone())
+        .encryption_context(HashMap::from_iter(context.clone()))
+        .send()
+        .await?;
+
+    let decrypted_plaintext = decryption_response
+        .plaintext
+        .ok_or_else(|| {
+            AppError::from_str(
+                "aws decrypt",
+                "Unable to unwrap plaintext from decryption response",
+            )
+        })?
+        .into_inner();
+
+    Ok(decrypted_plaintext)
+}
+
+/// Decrypts values encrypted with any KMS key owned by one of `account_ids` in
+/// `partition`, using the key ARN recorded in each ESDK message.  It cannot
+/// encrypt since it has no key to encrypt with.
+struct KmsDiscoveryEncryptionSystem {
+    esdk_client: esdk_client::Client,
+    kms_keyring: KeyringRef,
+    partition: String,
+    account_ids: Vec<String>,
+}
+
+/// Returns true if discovery for `account_ids` in `partition` should try the
+/// key `key_id`.  Key ARNs must pass the filter.  Key ids and aliases do not
+/// name their account so they are tried, and the keyring still applies the filter.
+pub(super) fn discovery_allows(partition: &str, account_ids: &[String], key_id: &str) -> bool {
+    if key_id.starts_with("kms:") {
+        return true;
+    }
+    let mut fields = key_id.split(':');
+    fields.next() == Some("arn")
+        && fields.next() == Some(partition)
+        && fields.next() == Some("kms")
+        && fields
+            .nth(1)
+            .is_some_and(|account| account_ids.iter().any(|a| a == account))
+}
+
+impl EncryptionSystem for KmsDiscoveryEncryptionSystem {
+    fn key_id(&self) -> Option<String> {
+        Some(format!(
+            "kms-discovery:{}:{}",
+            self.partition,
+            self.account_ids.join(",")
+        ))
+    }
+
+    fn can_decrypt(&self, key_id: &str) -> bool {
+        key_id
+            .split(KEY_SEPARATOR)
+            .any(|id| discovery_allows(&self.partition, &self.account_ids, id))
+    }
+
+    fn encrypt_bytes(&self, _: &[u8], _: &EncryptionContext) -> Result<String, AppError> {
+        Err(AppError::usage(
+            "KMS discovery can only decrypt, set CIPHER_KEY_ARN to a KMS key to encrypt",
+        ))
+    }
+
+    fn decrypt_bytes(
+        &self,
+        base64_ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        block_on(|| self.decrypt_bytes_async(base64_ciphertext, context))
+    }
+}
+
+#[async_trait]
+impl AsyncEncryptionSystem for KmsDiscoveryEncryptionSystem {
+    async fn encrypt_bytes_async(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        self.encrypt_bytes(plaintext, context)
+    }
+
+    async fn decrypt_bytes_async(
+        &self,
+        base64_ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        esdk_decrypt(
+            &self.esdk_client,
+            &self.kms_keyring,
+            base64_ciphertext,
+            context,
+        )
+        .await
+    }
+}
+
+/// Returns the region named in a key ARN, or `None` for key ids and aliases
+/// which use the default region.
+pub(super) fn key_region(key_id: &str) -> Option<&str> {
+    if !key_id.starts_with("arn:") {
+        return None;
+    }
+    key_id.split(':').nth(3).filter(|region| !region.is_empty())
+}
+
+/// Loads the AWS configuration, using `base_url` as the endpoint if given.
+async fn load_sdk_config(base_url: &Option<String>) -> aws_config::SdkConfig {
+    let mut sdk_config_loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if let Some(base_url) = base_url {
+        sdk_config_loader = sdk_config_loader.endpoint_url(base_url);
+    }
+    sdk_config_loader.load().await
+}
+
+/// Creates a KMS client for `region`, or for the default region if `None`.
+fn kms_client(sdk_config: &aws_config::SdkConfig, region: Option<&str>) -> aws_sdk_kms::Client {
+    let mut kms_config = aws_sdk_kms::config::Builder::from(sdk_config);
+    if let Some(region) = region {
+        kms_config = kms_config.region(aws_sdk_kms::config::Region::new(region.to_string()));
+    }
+    aws_sdk_kms::Client::from_conf(kms_config.build())
+}
+
+/// Creates a keyring for one KMS key using a client for the key's own region so
+/// that replica keys in other regions work.
+async fn kms_keyring(
+    mpl: &mpl_client::Client,
+    sdk_config: &aws_config::SdkConfig,
+    key_id: &str,
+) -> Result<KeyringRef, AppError> {
+    let kms_client = kms_client(sdk_config, key_region(key_id));
+    Ok(mpl
+        .create_aws_kms_keyring()
+        .kms_client(kms_client)
+        .kms_key_id(key_id)
+        .send()
+        .await?)
+}
+
+/// Key URI scheme for an asymmetric KMS key for RSA, optionally followed by `@`
+/// and the path to its public key in PEM format.  With the public key values are
+/// encrypted locally so only decrypting needs permission to use the KMS key.
+pub(super) const KMS_RSA_SCHEME: &str = "kms-rsa:";
+
+/// Creates a keyring for an asymmetric KMS key from a `kms-rsa:` key URI without
+/// the scheme, returning it with its key id.
+async fn kms_rsa_keyring(
+    mpl: &mpl_client::Client,
+    sdk_config: &aws_config::SdkConfig,
+    key: &str,
+) -> Result<(KeyringRef, String), AppError> {
+    // key ARNs and aliases never contain @ but paths might
+    let (key_id, public_key_path) = match key.split_once('@') {
+        Some((key_id, path)) => (key_id, Some(path)),
+        None => (key, None),
+    };
+    let kms_client = kms_client(sdk_config, key_region(key_id));
+    let mut builder = mpl
+        .create_aws_kms_rsa_keyring()
+        .kms_client(kms_client)
+        .kms_key_id(key_id)
+        .encryption_algorithm(EncryptionAlgorithmSpec::RsaesOaepSha256);
+    if let Some(path) = public_key_path {
+        builder = builder.public_key(read_rsa_public_key(path)?.into_bytes());
+    }
+    Ok((
+        builder.send().await?,
+        format!("{}{}", KMS_RSA_SCHEME, key_id),
+    ))
+}
+
+/// Async version of `create_kms_encryption()` for use inside an existing runtime.
+/// Requests are awaited rather than blocking the caller's thread, and values
+/// carry an envelope naming the keys (see `with_envelope()`) as they do when
+/// created by `create_encryption()`, so files written by the command line can
+/// be decrypted, including those written with a shared data key (see
+/// `with_data_key_reuse()`).
+pub async fn create_kms_encryption_async(
+    key_id: &str,
+    base_url: &Option<String>,
+) -> Result<Box<dyn AsyncEncryptionSystem>, AppError> {
+    Ok(with_envelope_async(with_data_key_reuse_async(
+        build_kms_encryption(key_id, base_url).await?,
+    )))
+}
+
+async fn build_kms_encryption(
+    key_id: &str,
+    base_url: &Option<String>,
+) -> Result<AwsEncryptionSystem, AppError> {
+    let key_ids: Vec<String> = key_id
+        .split(KEY_SEPARATOR)
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(String::from)
+        .collect();
+    if key_ids.is_empty() {
+        return Err(AppError::usage("no KMS key provided"));
+    }
+
+    let is_raw =
+        |key_id: &str| key_id.starts_with(RAW_AES_SCHEME) || key_id.starts_with(RAW_RSA_SCHEME);
+    // only load the AWS configuration when it is needed so that local keys work offline
+    let sdk_config = if key_ids.iter().all(|key_id| is_raw(key_id)) {
+        None
+    } else {
+        Some(load_sdk_config(base_url).await)
+    };
+
+    let mpl_config = MaterialProvidersConfig::builder().build()?;
+    let mpl = mpl_client::Client::from_conf(mpl_config)?;
+
+    let mut keyrings = Vec::new();
+    let mut ids = Vec::new();
+    for key_id in &key_ids {
+        let (keyring, id) = if let Some(path) = key_id.strip_prefix(RAW_AES_SCHEME) {
+            raw_aes_keyring(&mpl, path).await?
+        } else if let Some(path) = key_id.strip_prefix(RAW_RSA_SCHEME) {
+            raw_rsa_keyring(&mpl, path).await?
+        } else if let Some(key) = key_id.strip_prefix(KMS_RSA_SCHEME) {
+            let sdk_config = sdk_config.as_ref().expect("AWS configuration was loaded");
+            kms_rsa_keyring(&mpl, sdk_config, key).await?
+        } else {
+            let sdk_config = sdk_config.as_ref().expect("AWS configuration was loaded");
+            (
+                kms_keyring(&mpl, sdk_config, key_id).await?,
+                kms_key_id(key_id),
+            )
+        };
+        keyrings.push(keyring);
+        ids.push(id);
+    }
+    // the first key generates the data key and every other key also wraps it
+    let generator = keyrings.remove(0);
+    let kms_keyring = if keyrings.is_empty() {
+        generator
+    } else {
+        mpl.create_multi_keyring()
+            .generator(generator)
+            .child_keyrings(keyrings)
+            .send()
+            .await?
+    };
+
+    let esdk_config = AwsEncryptionSdkConfig::builder().build()?;
+    let esdk_client = esdk_client::Client::from_conf(esdk_config)?;
+
+    // asymmetric KMS keys cannot be used with suites that sign messages
+    let algorithm_suite = key_ids
+        .iter()
+        .any(|key_id| key_id.starts_with(KMS_RSA_SCHEME))
+        .then_some(EsdkAlgorithmSuiteId::AlgAes256GcmHkdfSha512CommitKey);
+
+    Ok(AwsEncryptionSystem {
+        esdk_client,
+        kms_keyring,
+        key_ids: ids,
+        algorithm_suite,
+    })
+}
+
+/// Create an encryption system using the KMS key `key_id` (a key id, alias or
+/// ARN).  Several keys can be given separated by `+`, in which case values are
+/// encrypted so that any one of them can decrypt them, for example a key and its
+/// replica in another region.  Keys can also be local keys used through the
+/// ESDK raw keyrings, given as `esdk-aes:` followed by the path to a key file
+/// or `esdk-rsa:` followed by the path to an RSA key in PEM format, and
+/// asymmetric KMS keys can be given as `kms-rsa:` followed by the key ARN and
+/// optionally `@` and the path to its public key (see `KMS_RSA_SCHEME`).
+pub fn create_kms_encryption(
+    key_id: &str,
+    base_url: &Option<String>,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let system = block_on(|| build_kms_encryption(key_id, base_url))?;
+    Ok(Box::new(system))
+}
+
+/// Async version of `create_kms_discovery_encryption()` for use inside an
+/// existing runtime, which like `create_kms_encryption_async()` awaits its
+/// requests and reads values with an envelope.
+pub async fn create_kms_discovery_encryption_async(
+    partition: &str,
+    account_ids: &[String],
+    regions: &[String],
+    base_url: &Option<String>,
+) -> Result<Box<dyn AsyncEncryptionSystem>, AppError> {
+    Ok(with_envelope_async(with_data_key_reuse_async(
+        build_kms_discovery_encryption(partition, account_ids, regions, base_url).await?,
+    )))
+}
+
+async fn build_kms_discovery_encryption(
+    partition: &str,
+    account_ids: &[String],
+    regions: &[String],
+    base_url: &Option<String>,
+) -> Result<KmsDiscoveryEncryptionSystem, AppError> {
+    if account_ids.is_empty() {
+        return Err(AppError::usage(
+            "KMS discovery requires at least one account id",
+        ));
+    }
+    let sdk_config = load_sdk_config(base_url).await;
+
+    let mpl_config = MaterialProvidersConfig::builder().build()?;
+    let mpl = mpl_client::Client::from_conf(mpl_config)?;
+
+    let discovery_filter = DiscoveryFilter::builder()
+        .partition(partition)
+        .account_ids(account_ids.to_vec())
+        .build()?;
+    // a discovery keyring only decrypts with keys in its client's region
+    let clients: Vec<aws_sdk_kms::Client> = if regions.is_empty() {
+        vec![kms_client(&sdk_config, None)]
+    } else {
+        regions
+            .iter()
+            .map(|region| kms_client(&sdk_config, Some(region)))
+            .collect()
+    };
+    let mut keyrings = Vec::new();
+    for client in clients {
+        keyrings.push(
+            mpl.create_aws_kms_discovery_keyring()
+                .kms_client(client)
+                .discovery_filter(discovery_filter.clone())
+                .send()
+                .await?,
+        );
+    }
+    let kms_keyring = if keyrings.len() == 1 {
+        keyrings.remove(0)
+    } else {
+        mpl.create_multi_keyring()
+            .child_keyrings(keyrings)
+            .send()
+            .await?
+    };
+
+    let esdk_config = AwsEncryptionSdkConfig::builder().build()?;
+    let esdk_client = esdk_client::Client::from_conf(esdk_config)?;
+
+    Ok(KmsDiscoveryEncryptionSystem {
+        esdk_client,
+        kms_keyring,
+        partition: partition.to_string(),
+        account_ids: account_ids.to_vec(),
+    })
+}
+
+/// Create an encryption system that decrypts values encrypted with any KMS key
+/// owned by one of `account_ids` in `partition` (such as `aws`), so readers do
+/// not need to know which key each file uses.  Keys are used in each of
+/// `regions`, or the default region if it is empty.
+pub fn create_kms_discovery_encryption(
+    partition: &str,
+    account_ids: &[String],
+    regions: &[String],
+    base_url: &Option<String>,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let system =
+        block_on(|| build_kms_discovery_encryption(partition, account_ids, regions, base_url))?;
+    Ok(Box::new(system))
+}
@@ -0,0 +1,243 @@
+use crate::app::AppError;
+use crate::encryption::envelope::{KEY_SEPARATOR, key_ids_match};
+use crate::encryption::{EncryptionContext, EncryptionSystem, PassphraseSource, reject_context};
+use aes_gcm::aead::OsRng;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use pgp::composed::{
+    ArmorOptions, Deserializable, Message, MessageBuilder, SignedPublicKey, SignedPublicSubKey,
+    SignedSecretKey,
+};
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::types::{KeyDetails, Password};
+use std::fs::read;
+use std::sync::Arc;
+
+/// Key id prefix for OpenPGP keys, followed by the fingerprint of the primary key.
+const FINGERPRINT_PREFIX: &str = "pgp:";
+
+/// The line that starts an ASCII armored OpenPGP message.
+const ARMOR_BEGIN: &str = "-----BEGIN PGP MESSAGE-----";
+
+/// The start of the line that begins any ASCII armored block.
+const ARMOR_START: &str = "-----BEGIN ";
+
+/// Encrypts values as OpenPGP messages to the encryption subkeys of one or more
+/// public keys, so that they can also be decrypted with `gpg`.  Values are base64
+/// encoded messages, or ASCII armored messages when `armor` is set.
+///
+/// Messages use SEIPDv1 which any OpenPGP implementation can read.  It has no
+/// associated data so a non-empty encryption context is refused (see
+/// `reject_context()`).
+struct OpenPgpEncryptionSystem {
+    recipients: Vec<SignedPublicKey>,
+    secret_key: Option<SignedSecretKey>,
+    /// Unlocks a secret key that is protected with a passphrase.
+    passphrase: Arc<PassphraseSource>,
+    armor: bool,
+}
+
+fn pgp_error(context: &str, error: impl std::fmt::Display) -> AppError {
+    AppError::from_str(context, error.to_string().as_str())
+}
+
+fn fingerprint_id(key: &impl KeyDetails) -> String {
+    format!("{}{:X}", FINGERPRINT_PREFIX, key.fingerprint())
+}
+
+/// The subkeys that are marked for encryption and use an algorithm that can.
+fn encryption_subkeys(key: &SignedPublicKey) -> Vec<&SignedPublicSubKey> {
+    key.public_subkeys
+        .iter()
+        .filter(|subkey| subkey.key.algorithm().can_encrypt())
+        .filter(|subkey| {
+            subkey.signatures.iter().any(|signature| {
+                let flags = signature.key_flags();
+                flags.encrypt_comms() || flags.encrypt_storage()
+            })
+        })
+        .collect()
+}
+
+impl OpenPgpEncryptionSystem {
+    /// Returns the password for the secret key, asking for the passphrase only
+    /// when the key is protected with one.
+    fn password(&self, secret_key: &SignedSecretKey) -> Result<Password, AppError> {
+        let locked = secret_key.primary_key.secret_params().is_encrypted()
+            || secret_key
+                .secret_subkeys
+                .iter()
+                .any(|subkey| subkey.key.secret_params().is_encrypted());
+        if locked {
+            Ok(Password::from((self.passphrase)()?))
+        } else {
+            Ok(Password::empty())
+        }
+    }
+}
+
+impl EncryptionSystem for OpenPgpEncryptionSystem {
+    /// The fingerprints of the recipients, so that blocks show who can decrypt them.
+    fn key_id(&self) -> Option<String> {
+        let ids: Vec<String> = self.recipients.iter().map(fingerprint_id).collect();
+        Some(ids.join(&KEY_SEPARATOR.to_string()))
+    }
+
+    /// Any value encrypted to our secret key can be decrypted, even when the key
+    /// is not one of the recipients.  Without a secret key values for our
+    /// recipients are accepted so that decrypting explains what is missing.
+    fn can_decrypt(&self, key_id: &str) -> bool {
+        match &self.secret_key {
+            Some(secret_key) => {
+                let own = fingerprint_id(&secret_key.primary_key);
+                key_id.split(KEY_SEPARATOR).any(|id| id == own)
+            }
+            None => self.key_id().is_some_and(|own| key_ids_match(key_id, &own)),
+        }
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        reject_context("OpenPGP", context)?;
+        let mut builder = MessageBuilder::from_bytes("", plaintext.to_vec())
+            .seipd_v1(OsRng, SymmetricKeyAlgorithm::AES256);
+        for recipient in &self.recipients {
+            for subkey in encryption_subkeys(recipient) {
+                builder
+                    .encrypt_to_key(OsRng, subkey)
+                    .map_err(|e| pgp_error("pgp encrypt", e))?;
+            }
+        }
+        if self.armor {
+            builder
+                .to_armored_string(OsRng, ArmorOptions::default())
+                .map_err(|e| pgp_error("pgp encrypt", e))
+        } else {
+            let message = builder
+                .to_vec(OsRng)
+                .map_err(|e| pgp_error("pgp encrypt", e))?;
+            Ok(STANDARD.encode(message))
+        }
+    }
+
+    fn decrypt_bytes(
+        &self,
+        ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        reject_context("OpenPGP", context)?;
+        let tampered =
+            |e: pgp::errors::Error| AppError::tampered("pgp decrypt", e.to_string().as_str());
+        let Some(secret_key) = &self.secret_key else {
+            return Err(AppError::usage(
+                "no OpenPGP secret key provided to decrypt with (see CIPHER_PGP_SECRET_KEY)",
+            ));
+        };
+        let password = self.password(secret_key)?;
+        let bytes;
+        let message = if ciphertext.trim_start().starts_with(ARMOR_BEGIN) {
+            Message::from_armor(ciphertext.trim().as_bytes())
+                .map_err(tampered)?
+                .0
+        } else {
+            let compact: String = ciphertext.chars().filter(|c| !c.is_whitespace()).collect();
+            bytes = STANDARD
+                .decode(compact)
+                .map_err(|e| AppError::tampered("pgp decrypt", e.to_string().as_str()))?;
+            Message::from_bytes(bytes.as_slice()).map_err(tampered)?
+        };
+        let mut message = message.decrypt(&password, secret_key).map_err(tampered)?;
+        if message.is_compressed() {
+            message = message.decompress().map_err(tampered)?;
+        }
+        // the integrity check runs as the data is read
+        message
+            .as_data_vec()
+            .map_err(|e| AppError::tampered("pgp decrypt", e.to_string().as_str()))
+    }
+}
+
+/// Reads every OpenPGP key in the file at `path`, which is either binary or
+/// holds one or more ASCII armored blocks (as when exported keys are
+/// concatenated).
+fn read_keys<T: Deserializable>(path: &str, what: &str) -> Result<Vec<T>, AppError> {
+    let contents = read(path)?;
+    let invalid = |e: pgp::errors::Error| {
+        AppError::usage(format!("invalid {} in {}: {}", what, path, e).as_str())
+    };
+    // the armor reader stops at the end of the first block
+    let text = String::from_utf8_lossy(&contents);
+    let blocks: Vec<&[u8]> = if text.trim_start().starts_with(ARMOR_START) {
+        text.match_indices(ARMOR_START)
+            .map(|(start, _)| start)
+            .chain([text.len()])
+            .collect::<Vec<_>>()
+            .windows(2)
+            .map(|range| text[range[0]..range[1]].as_bytes())
+            .collect()
+    } else {
+        vec![contents.as_slice()]
+    };
+    let mut keys = Vec::new();
+    for block in blocks {
+        for key in T::from_reader_many(block).map_err(invalid)?.0 {
+            keys.push(key.map_err(invalid)?);
+        }
+    }
+    if keys.is_empty() {
+        return Err(AppError::usage(
+            format!("no {} found in {}", what, path).as_str(),
+        ));
+    }
+    Ok(keys)
+}
+
+/// Create an `EncryptionSystem` that encrypts values as OpenPGP messages to the
+/// public keys in the file at `public_keys_path`, such as the output of
+/// `gpg --export --armor alice bob`.  Values are decrypted with the secret key
+/// in the file at `secret_key_path` (from `gpg --export-secret-keys`), which is
+/// unlocked with `passphrase` when it is protected with one.  ASCII armored
+/// values are written when `armor` is set.
+pub fn create_openpgp_encryption(
+    public_keys_path: &str,
+    secret_key_path: Option<&str>,
+    passphrase: Arc<PassphraseSource>,
+    armor: bool,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let recipients: Vec<SignedPublicKey> = read_keys(public_keys_path, "OpenPGP public key")?;
+    for recipient in &recipients {
+        recipient
+            .verify_bindings()
+            .map_err(|e| pgp_error("pgp public key", e))?;
+        if encryption_subkeys(recipient).is_empty() {
+            return Err(AppError::usage(
+                format!(
+                    "OpenPGP key {:X} in {} has no encryption subkey",
+                    recipient.fingerprint(),
+                    public_keys_path
+                )
+                .as_str(),
+            ));
+        }
+    }
+    let secret_key = match secret_key_path {
+        Some(path) => {
+            let mut keys: Vec<SignedSecretKey> = read_keys(path, "OpenPGP secret key")?;
+            if keys.len() > 1 {
+                return Err(AppError::usage(
+                    format!("{} holds more than one OpenPGP secret key", path).as_str(),
+                ));
+            }
+            Some(keys.remove(0))
+        }
+        None => None,
+    };
+    Ok(Box::new(OpenPgpEncryptionSystem {
+        recipients,
+        secret_key,
+        passphrase,
+        armor,
+    }))
+}
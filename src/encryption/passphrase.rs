@@ -0,0 +1,236 @@
+use crate::app::AppError;
+use crate::encryption::{EncryptionContext, EncryptionSystem, context_aad};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+use std::fs::File;
+use std::io::Read;
+use std::mem::ManuallyDrop;
+use std::os::fd::FromRawFd;
+
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const SALT_OFFSET: usize = 1 + 3 * 4;
+const HEADER_LEN: usize = SALT_OFFSET + SALT_LEN;
+
+/// Refuse to honor KDF parameters from a payload that would need more than 1 GiB,
+/// or so many passes or lanes that decrypting would effectively never finish.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+/// Default Argon2id cost parameters (19 MiB, 2 iterations, 1 lane) as
+/// recommended by OWASP.
+pub const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+pub const DEFAULT_ITERATIONS: u32 = 2;
+pub const DEFAULT_PARALLELISM: u32 = 1;
+
+impl From<argon2::Error> for AppError {
+    fn from(error: argon2::Error) -> Self {
+        AppError::from_str("argon2 error", error.to_string().as_str())
+    }
+}
+
+/// Payloads start with a header containing the format version, the Argon2id
+/// memory, iteration and parallelism costs (big endian u32s), and the salt.
+/// The header is followed by the nonce and the GCM ciphertext.  The header and
+/// the encryption context are used as the associated data so neither can be
+/// altered undetected.
+struct PassphraseEncryptionSystem {
+    passphrase: String,
+    params: Params,
+}
+
+impl PassphraseEncryptionSystem {
+    fn derive_cipher(&self, params: Params, salt: &[u8]) -> Result<Aes256Gcm, AppError> {
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+            self.passphrase.as_bytes(),
+            salt,
+            &mut key,
+        )?;
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+}
+
+impl EncryptionSystem for PassphraseEncryptionSystem {
+    /// Passphrases have no identity that can be shared safely.
+    fn key_id(&self) -> Option<String> {
+        Some("pass:".to_string())
+    }
+
+    fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        context: &EncryptionContext,
+    ) -> Result<String, AppError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = self.derive_cipher(self.params.clone(), &salt)?;
+
+        let mut header = vec![FORMAT_VERSION];
+        header.extend_from_slice(&self.params.m_cost().to_be_bytes());
+        header.extend_from_slice(&self.params.t_cost().to_be_bytes());
+        header.extend_from_slice(&self.params.p_cost().to_be_bytes());
+        header.extend_from_slice(&salt);
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = [header.as_slice(), &context_aad(context)].concat();
+        let payload = Payload {
+            msg: plaintext,
+            aad: &aad,
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| AppError::from_str("passphrase encrypt", "unable to encrypt plaintext"))?;
+
+        let mut answer = header;
+        answer.extend_from_slice(&nonce);
+        answer.extend_from_slice(&ciphertext);
+        Ok(URL_SAFE.encode(answer))
+    }
+
+    fn decrypt_bytes(
+        &self,
+        base64_ciphertext: &str,
+        context: &EncryptionContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let bytes = URL_SAFE.decode(base64_ciphertext.as_bytes())?;
+        if bytes.len() < HEADER_LEN + NONCE_LEN {
+            return Err(AppError::tampered(
+                "passphrase decrypt",
+                "ciphertext is too short",
+            ));
+        }
+        if bytes[0] != FORMAT_VERSION {
+            return Err(AppError::from_str(
+                "passphrase decrypt",
+                format!("unsupported format version {}", bytes[0]).as_str(),
+            ));
+        }
+
+        let (header, rest) = bytes.split_at(HEADER_LEN);
+        let cost = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap());
+        let (m_cost, t_cost, p_cost) = (cost(1), cost(5), cost(9));
+        if m_cost > MAX_MEMORY_KIB {
+            return Err(AppError::from_str(
+                "passphrase decrypt",
+                format!("argon2 memory cost of {} KiB is too large", m_cost).as_str(),
+            ));
+        }
+        if t_cost > MAX_ITERATIONS || p_cost > MAX_PARALLELISM {
+            return Err(AppError::from_str(
+                "passphrase decrypt",
+                format!(
+                    "argon2 cost of {} iterations and {} lanes is too large",
+                    t_cost, p_cost
+                )
+                .as_str(),
+            ));
+        }
+        let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN))?;
+        let cipher = self.derive_cipher(params, &header[SALT_OFFSET..])?;
+
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let aad = [header, &context_aad(context)].concat();
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                AppError::tampered(
+                    "passphrase decrypt",
+                    "ciphertext failed authentication (wrong passphrase, wrong context or tampered data)",
+                )
+            })?;
+
+        Ok(plaintext)
+    }
+}
+
+/// Create a new `EncryptionSystem` that uses AES-256-GCM with keys derived from
+/// `passphrase` using Argon2id and the default cost parameters.
+///
+/// Every encrypted value gets its own random salt.  The salt and cost parameters
+/// are stored in the encrypted value so the defaults can be changed without
+/// breaking the ability to decrypt older values.
+pub fn create_passphrase_encryption(
+    passphrase: &str,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    create_passphrase_encryption_with_params(
+        passphrase,
+        DEFAULT_MEMORY_KIB,
+        DEFAULT_ITERATIONS,
+        DEFAULT_PARALLELISM,
+    )
+}
+
+/// Same as `create_passphrase_encryption()` but uses the provided Argon2id cost
+/// parameters when encrypting.  Decryption always uses the parameters stored
+/// in the encrypted value.
+pub fn create_passphrase_encryption_with_params(
+    passphrase: &str,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    if passphrase.is_empty() {
+        return Err(AppError::usage("passphrase is empty"));
+    }
+    let params = Params::new(memory_kib, iterations, parallelism, Some(KEY_LEN))?;
+    Ok(Box::new(PassphraseEncryptionSystem {
+        passphrase: passphrase.to_string(),
+        params,
+    }))
+}
+
+/// Read a passphrase from the first line of the already open file descriptor `fd`,
+/// such as one created by a shell redirection like `3<passphrase.txt`.  The
+/// descriptor is left open and nothing after the first line is read from it.
+pub fn read_passphrase_fd(fd: i32) -> Result<String, AppError> {
+    // SAFETY: F_GETFD only inspects the descriptor table and fails for any
+    // number that is not an open descriptor.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(AppError::usage(
+            format!("file descriptor {} is not open", fd).as_str(),
+        ));
+    }
+    // SAFETY: `fd` is open, and `ManuallyDrop` keeps the `File` from closing a
+    // descriptor that it does not own.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    // read a byte at a time so that a shared descriptor such as stdin is not
+    // read past the passphrase
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while (&*file).read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8(line)?;
+    Ok(line.trim_end_matches('\r').to_string())
+}
+
+/// Prompt for a passphrase on the terminal without echoing it.  When `confirm`
+/// is true the passphrase must be entered twice to guard against typos.
+pub fn prompt_passphrase(confirm: bool) -> Result<String, AppError> {
+    let passphrase = rpassword::prompt_password("Passphrase: ")?;
+    if confirm && passphrase != rpassword::prompt_password("Confirm passphrase: ")? {
+        return Err(AppError::usage("passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+/// Prompt for a new passphrase on the terminal, such as the one `rotate`
+/// re-encrypts with, asking for it twice to guard against typos.
+pub fn prompt_new_passphrase() -> Result<String, AppError> {
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if passphrase != rpassword::prompt_password("Confirm new passphrase: ")? {
+        return Err(AppError::usage("passphrases do not match"));
+    }
+    Ok(passphrase)
+}
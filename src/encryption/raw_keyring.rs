@@ -0,0 +1,116 @@
+use crate::app::AppError;
+use crate::encryption::key_file::{fingerprint, read_key_file};
+use aws_esdk::material_providers::client as mpl_client;
+use aws_esdk::material_providers::types::keyring::KeyringRef;
+use aws_esdk::material_providers::types::{AesWrappingAlg, PaddingScheme};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{
+    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
+};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use std::fs::read_to_string;
+
+/// Key URI scheme for an AES key file (as written by `generate_key_file()`)
+/// used through an ESDK raw AES keyring.
+pub(super) const RAW_AES_SCHEME: &str = "esdk-aes:";
+
+/// Key URI scheme for a PEM file holding an RSA public or private key used
+/// through an ESDK raw RSA keyring.
+pub(super) const RAW_RSA_SCHEME: &str = "esdk-rsa:";
+
+/// Stored in each encrypted data key so that the keyring that wrapped it can be
+/// found.  Keys are named by fingerprint so that copies of a key file in
+/// different places can decrypt each other's values.
+const KEY_NAMESPACE: &str = "cipher";
+
+/// Creates a raw AES keyring for the key file at `path`, returning it with its
+/// key id.
+pub(super) async fn raw_aes_keyring(
+    mpl: &mpl_client::Client,
+    path: &str,
+) -> Result<(KeyringRef, String), AppError> {
+    let key = read_key_file(path)?;
+    let key_name = format!("sha256:{}", fingerprint(&key));
+    let keyring = mpl
+        .create_raw_aes_keyring()
+        .key_namespace(KEY_NAMESPACE)
+        .key_name(key_name.clone())
+        .wrapping_key(key)
+        .wrapping_alg(AesWrappingAlg::AlgAes256GcmIv12Tag16)
+        .send()
+        .await?;
+    Ok((keyring, format!("{}{}", RAW_AES_SCHEME, key_name)))
+}
+
+/// The PEM encodings the ESDK expects for an RSA key pair.  The private key is
+/// only known when the file holds it.
+struct RsaKeyPem {
+    public: String,
+    private: Option<String>,
+    fingerprint: String,
+}
+
+fn invalid_rsa_key(path: &str, detail: impl std::fmt::Display) -> AppError {
+    AppError::usage(format!("invalid RSA key file {}: {}", path, detail).as_str())
+}
+
+/// Reads an RSA key in PKCS #8 or PKCS #1 PEM format.  A private key is enough
+/// to both encrypt and decrypt, while a public key can only encrypt.
+fn read_rsa_key(path: &str) -> Result<RsaKeyPem, AppError> {
+    let pem = read_to_string(path)?;
+    let private = if pem.contains("PRIVATE KEY-----") {
+        let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|e| invalid_rsa_key(path, e))?;
+        Some(key)
+    } else {
+        None
+    };
+    let public = match &private {
+        Some(key) => key.to_public_key(),
+        None => RsaPublicKey::from_public_key_pem(&pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(&pem))
+            .map_err(|e| invalid_rsa_key(path, e))?,
+    };
+    let public_der = public
+        .to_public_key_der()
+        .map_err(|e| invalid_rsa_key(path, e))?;
+    Ok(RsaKeyPem {
+        public: public
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|e| invalid_rsa_key(path, e))?,
+        private: private
+            .map(|key| key.to_pkcs8_pem(LineEnding::LF).map(|pem| pem.to_string()))
+            .transpose()
+            .map_err(|e| invalid_rsa_key(path, e))?,
+        fingerprint: fingerprint(public_der.as_bytes()),
+    })
+}
+
+/// Reads the public key from an RSA key file (see `read_rsa_key()`) in the PEM
+/// format the ESDK expects.
+pub(super) fn read_rsa_public_key(path: &str) -> Result<String, AppError> {
+    Ok(read_rsa_key(path)?.public)
+}
+
+/// Creates a raw RSA keyring for the PEM file at `path`, returning it with its
+/// key id.  The key id is based on the public key so that values encrypted with
+/// the public key name the same key as the private key that decrypts them.
+pub(super) async fn raw_rsa_keyring(
+    mpl: &mpl_client::Client,
+    path: &str,
+) -> Result<(KeyringRef, String), AppError> {
+    let key = read_rsa_key(path)?;
+    let key_name = format!("sha256:{}", key.fingerprint);
+    let mut builder = mpl
+        .create_raw_rsa_keyring()
+        .key_namespace(KEY_NAMESPACE)
+        .key_name(key_name.clone())
+        .padding_scheme(PaddingScheme::OaepSha256Mgf1)
+        .public_key(key.public.into_bytes());
+    if let Some(private) = key.private {
+        builder = builder.private_key(private.into_bytes());
+    }
+    let keyring = builder.send().await?;
+    Ok((keyring, format!("{}{}", RAW_RSA_SCHEME, key_name)))
+}
@@ -0,0 +1,351 @@
+use crate::app::AppError;
+use crate::encryption::envelope::KEY_SEPARATOR;
+use crate::encryption::{
+    EncryptionSystem, create_key_file_encryption, create_passphrase_encryption,
+    new_insecure_encryption, with_data_key_reuse, with_envelope,
+};
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::sync::{Arc, RwLock};
+
+/// Called by backends that need a passphrase.
+pub type PassphraseSource = dyn Fn() -> Result<String, AppError> + Send + Sync;
+
+/// Settings a backend may need in addition to its key URI.  Start from
+/// `BackendOptions::default()` and set the fields that are needed, as more
+/// fields may be added.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct BackendOptions {
+    /// Endpoint to use instead of the service default, such as a localstack
+    /// container (see `CIPHER_BASE_URL`).
+    pub base_url: Option<String>,
+    /// Called by backends that need a passphrase.  Only called when needed so
+    /// that users are not prompted for a passphrase that will not be used.
+    pub passphrase: Arc<PassphraseSource>,
+    /// Share one data key between the values encrypted in this run (see
+    /// `with_data_key_reuse()`).
+    pub reuse_data_key: bool,
+    /// Path to the file of age identities used to decrypt `age:` values (see
+    /// `CIPHER_AGE_IDENTITY`).
+    pub age_identity: Option<String>,
+    /// Path to the OpenPGP secret key used to decrypt `pgp:` values (see
+    /// `CIPHER_PGP_SECRET_KEY`).
+    pub pgp_secret_key: Option<String>,
+}
+
+impl Default for BackendOptions {
+    /// No endpoint override, data key reuse, age identity or OpenPGP secret key,
+    /// and a passphrase source that reports a usage error.
+    fn default() -> Self {
+        BackendOptions {
+            base_url: None,
+            passphrase: Arc::new(|| Err(AppError::usage("no passphrase available"))),
+            reuse_data_key: false,
+            age_identity: None,
+            pgp_secret_key: None,
+        }
+    }
+}
+
+/// Creates an `EncryptionSystem` from a complete key URI (including the scheme).
+pub type BackendFactory =
+    dyn Fn(&str, &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> + Send + Sync;
+
+/// The key URI used before schemes existed to select `debug:`.
+const LEGACY_DEBUG_KEY: &str = "DEBUG";
+
+/// Returns `key` with the legacy `DEBUG` key replaced by `debug:`, and a key
+/// without a scheme (a KMS key id or `alias/name`, as `CIPHER_KEY_ARN` took
+/// before schemes existed) prefixed with `kms:`.
+pub(crate) fn canonical_key(key: &str) -> Cow<'_, str> {
+    if key == LEGACY_DEBUG_KEY {
+        Cow::Borrowed("debug:")
+    } else if !key.is_empty() && !key.contains(':') {
+        Cow::Owned(format!("kms:{}", key))
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+/// Returns the scheme of `id` (the text up to and including the first `:`).
+fn scheme_of(id: &str) -> &str {
+    id.find(':').map_or(id, |end| &id[..=end])
+}
+
+/// Returns true if the backend for `key` might decrypt values whose envelope
+/// names `key_id`, judging only by the schemes of the ids that the backend
+/// writes, so that backends which certainly cannot are never created.
+pub(crate) fn could_decrypt(key: &str, key_id: &str) -> bool {
+    let key = canonical_key(key);
+    let kms = |id: &str| id.starts_with("arn:") || id.starts_with("kms:");
+    key.split(KEY_SEPARATOR).any(|member| {
+        key_id.split(KEY_SEPARATOR).any(|id| {
+            // discovery decrypts values from any KMS key
+            if member.starts_with(KMS_DISCOVERY_SCHEME) || kms(member) {
+                kms(id)
+            } else {
+                // armored and plain values share their key ids
+                scheme_of(member).replace("-armor:", ":") == scheme_of(id)
+            }
+        })
+    })
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Vec<(String, Arc<BackendFactory>)>> =
+        RwLock::new(default_backends());
+}
+
+fn default_backends() -> Vec<(String, Arc<BackendFactory>)> {
+    let mut answer: Vec<(String, Arc<BackendFactory>)> = vec![
+        (
+            "debug:".to_string(),
+            Arc::new(|_: &str, _: &BackendOptions| new_insecure_encryption()),
+        ),
+        (
+            "file:".to_string(),
+            Arc::new(|key: &str, _: &BackendOptions| {
+                create_key_file_encryption(&key["file:".len()..])
+            }),
+        ),
+        (
+            "pass:".to_string(),
+            Arc::new(|_: &str, options: &BackendOptions| {
+                create_passphrase_encryption(&(options.passphrase)()?)
+            }),
+        ),
+    ];
+    for scheme in KMS_SCHEMES
+        .iter()
+        .chain(&RAW_KEYRING_SCHEMES)
+        .chain(&[KMS_RSA_SCHEME])
+    {
+        answer.push((scheme.to_string(), Arc::new(kms_backend)));
+    }
+    answer.push((
+        KMS_DISCOVERY_SCHEME.to_string(),
+        Arc::new(kms_discovery_backend),
+    ));
+    for scheme in AGE_SCHEMES {
+        answer.push((scheme.to_string(), Arc::new(age_backend)));
+    }
+    for scheme in PGP_SCHEMES {
+        answer.push((scheme.to_string(), Arc::new(pgp_backend)));
+    }
+    answer
+}
+
+/// `pgp:` and `pgp-armor:` are followed by the path to a file of OpenPGP public
+/// keys.  The `armor` scheme writes ASCII armored values instead of base64
+/// encoded ones.
+const PGP_SCHEMES: [&str; 2] = ["pgp:", "pgp-armor:"];
+
+#[cfg(feature = "openpgp")]
+fn pgp_backend(key: &str, options: &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let (scheme, path) = key.split_once(':').unwrap_or((key, ""));
+    crate::encryption::create_openpgp_encryption(
+        path,
+        options.pgp_secret_key.as_deref(),
+        Arc::clone(&options.passphrase),
+        scheme.ends_with("-armor"),
+    )
+}
+
+#[cfg(not(feature = "openpgp"))]
+fn pgp_backend(key: &str, _: &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    Err(AppError::usage(
+        format!(
+            "{} needs the openpgp feature which this build of cipher does not include",
+            key
+        )
+        .as_str(),
+    ))
+}
+
+/// `age:` and `age-armor:` are followed by the path to a file of recipients and
+/// `age-scrypt:` uses a passphrase.  The `armor` schemes write ASCII armored
+/// values instead of base64 encoded ones.
+const AGE_SCHEMES: [&str; 4] = ["age:", "age-armor:", "age-scrypt:", "age-scrypt-armor:"];
+
+#[cfg(feature = "age")]
+fn age_backend(key: &str, options: &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let (scheme, rest) = key.split_once(':').unwrap_or((key, ""));
+    let armor = scheme.ends_with("-armor");
+    if scheme.starts_with("age-scrypt") {
+        crate::encryption::create_age_passphrase_encryption(&(options.passphrase)()?, armor)
+    } else {
+        crate::encryption::create_age_encryption(rest, options.age_identity.as_deref(), armor)
+    }
+}
+
+#[cfg(not(feature = "age"))]
+fn age_backend(key: &str, _: &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    Err(AppError::usage(
+        format!(
+            "{} needs the age feature which this build of cipher does not include",
+            key
+        )
+        .as_str(),
+    ))
+}
+
+/// KMS keys can be given as ARNs in any partition, or as `kms:` followed by a
+/// key id, alias name (`kms:alias/name`) or ARN.  Several keys separated by `+`
+/// encrypt values that any one of them can decrypt.
+const KMS_SCHEMES: [&str; 4] = [
+    "arn:aws:kms:",
+    "arn:aws-cn:kms:",
+    "arn:aws-us-gov:kms:",
+    "kms:",
+];
+
+/// Local keys used through the ESDK raw AES and RSA keyrings, which can be
+/// combined with KMS keys using `+`.
+const RAW_KEYRING_SCHEMES: [&str; 2] = ["esdk-aes:", "esdk-rsa:"];
+
+/// Asymmetric KMS keys, where anyone with the public key can encrypt:
+/// `kms-rsa:<key ARN>[@<path to public key>]`.
+const KMS_RSA_SCHEME: &str = "kms-rsa:";
+
+/// Decrypts with any KMS key in the listed accounts:
+/// `kms-discovery:<partition>:<account>[,<account>...][:<region>[,<region>...]]`.
+const KMS_DISCOVERY_SCHEME: &str = "kms-discovery:";
+
+/// The partition, account ids and regions of a `kms-discovery:` key URI.
+pub(crate) type DiscoverySettings = (String, Vec<String>, Vec<String>);
+
+/// Splits a `kms-discovery:` key URI into its partition, account ids and regions.
+pub(crate) fn parse_discovery_key(key: &str) -> Result<DiscoverySettings, AppError> {
+    let list = |s: &str| -> Vec<String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    let rest = key.strip_prefix(KMS_DISCOVERY_SCHEME).unwrap_or(key);
+    let fields: Vec<&str> = rest.split(':').collect();
+    match fields.as_slice() {
+        [partition, accounts] | [partition, accounts, _]
+            if !partition.is_empty() && !list(accounts).is_empty() =>
+        {
+            let regions = fields.get(2).map(|r| list(r)).unwrap_or_default();
+            Ok((partition.to_string(), list(accounts), regions))
+        }
+        _ => Err(AppError::usage(
+            format!(
+                "invalid KMS discovery key {}, expected {}<partition>:<account ids>[:<regions>]",
+                key, KMS_DISCOVERY_SCHEME
+            )
+            .as_str(),
+        )),
+    }
+}
+
+#[cfg(feature = "aws-kms")]
+fn kms_discovery_backend(
+    key: &str,
+    options: &BackendOptions,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let (partition, account_ids, regions) = parse_discovery_key(key)?;
+    crate::encryption::create_kms_discovery_encryption(
+        &partition,
+        &account_ids,
+        &regions,
+        &options.base_url,
+    )
+}
+
+#[cfg(not(feature = "aws-kms"))]
+fn kms_discovery_backend(
+    key: &str,
+    options: &BackendOptions,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    parse_discovery_key(key)?;
+    kms_backend(key, options)
+}
+
+#[cfg(feature = "aws-kms")]
+fn kms_backend(key: &str, options: &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    // each of several keys may have its own kms: prefix
+    let key_ids: Vec<&str> = key
+        .split(KEY_SEPARATOR)
+        .map(|k| k.strip_prefix("kms:").unwrap_or(k))
+        .collect();
+    crate::encryption::create_kms_encryption(
+        &key_ids.join(&KEY_SEPARATOR.to_string()),
+        &options.base_url,
+    )
+}
+
+#[cfg(not(feature = "aws-kms"))]
+fn kms_backend(key: &str, _: &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    Err(AppError::usage(
+        format!(
+            "{} needs the aws-kms feature which this build of cipher does not include",
+            key
+        )
+        .as_str(),
+    ))
+}
+
+/// Register `factory` to create the encryption system for key URIs that start
+/// with `scheme` (for example `vault:`).  Registering a scheme that already
+/// exists replaces it, which includes the built in `debug:`, `file:`, `pass:`,
+/// `kms:`, `kms-discovery:`, `kms-rsa:`, `esdk-aes:`, `esdk-rsa:`, `age:`, `pgp:`
+/// and `arn:aws:kms:` schemes.  When several schemes match a key the
+/// longest one is used.
+pub fn register_backend<F>(scheme: &str, factory: F)
+where
+    F: Fn(&str, &BackendOptions) -> Result<Box<dyn EncryptionSystem>, AppError>
+        + Send
+        + Sync
+        + 'static,
+{
+    let mut registry = REGISTRY.write().unwrap();
+    registry.retain(|(s, _)| s != scheme);
+    registry.push((scheme.to_string(), Arc::new(factory)));
+}
+
+/// Returns the registered schemes in the order they were registered.
+pub fn backend_schemes() -> Vec<String> {
+    let registry = REGISTRY.read().unwrap();
+    registry.iter().map(|(scheme, _)| scheme.clone()).collect()
+}
+
+/// Create the encryption system for `key` using the backend registered for its
+/// scheme (see `register_backend()`).  The legacy key `DEBUG` means `debug:`.
+/// The system is wrapped to support data key reuse and envelopes (see
+/// `with_data_key_reuse()` and `with_envelope()`).  A key without a scheme is
+/// taken to be a KMS key id or alias.
+pub fn create_encryption(
+    key: &str,
+    options: &BackendOptions,
+) -> Result<Box<dyn EncryptionSystem>, AppError> {
+    let key = canonical_key(key);
+    let key = key.as_ref();
+    // the lock is released before calling the factory so factories can register backends
+    let factory = {
+        let registry = REGISTRY.read().unwrap();
+        registry
+            .iter()
+            .filter(|(scheme, _)| key.starts_with(scheme.as_str()))
+            .max_by_key(|(scheme, _)| scheme.len())
+            .map(|(_, factory)| Arc::clone(factory))
+    };
+    match factory {
+        Some(factory) => Ok(with_envelope(with_data_key_reuse(
+            factory(key, options)?,
+            options.reuse_data_key,
+        ))),
+        None => Err(AppError::usage(
+            format!(
+                "unknown key scheme in {}, expected one of: {}",
+                key,
+                backend_schemes().join(", ")
+            )
+            .as_str(),
+        )),
+    }
+}
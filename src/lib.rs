@@ -1,5 +1,7 @@
 pub mod app;
 pub mod encryption;
+#[cfg(test)]
+mod test_util;
 
 #[macro_use(defer)]
 extern crate scopeguard;
@@ -1,47 +1,194 @@
 use cipher::app;
 use cipher::app::AppError;
 use cipher::encryption;
+use cipher::encryption::{BackendOptions, PassphraseSource};
 use std::env;
-use std::string::ToString;
+use std::process::ExitCode;
+use std::sync::{Arc, OnceLock};
 
-fn main() -> Result<(), AppError> {
-    let mut args = env::args();
+/// Find the passphrase using `CIPHER_PASSPHRASE`, then `CIPHER_PASSPHRASE_FD`,
+/// then finally prompting on the terminal.  When prompting, `confirm` asks for
+/// the passphrase twice, as commands that encrypt values do.
+fn get_passphrase(confirm: bool) -> Result<String, AppError> {
+    if let Ok(passphrase) = env::var("CIPHER_PASSPHRASE") {
+        Ok(passphrase)
+    } else if let Ok(fd) = env::var("CIPHER_PASSPHRASE_FD") {
+        let fd = fd.parse::<i32>().map_err(|e| {
+            AppError::usage(format!("invalid CIPHER_PASSPHRASE_FD: {}", e).as_str())
+        })?;
+        encryption::read_passphrase_fd(fd)
+    } else {
+        encryption::prompt_passphrase(confirm)
+    }
+}
+
+/// Find the passphrase that `rotate` re-encrypts `pass:` and `age-scrypt:` blocks
+/// with using `CIPHER_NEW_PASSPHRASE`, or else prompting on the terminal.
+fn get_new_passphrase() -> Result<String, AppError> {
+    match env::var("CIPHER_NEW_PASSPHRASE") {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => encryption::prompt_new_passphrase(),
+    }
+}
+
+/// Returns a passphrase source that calls `source` at most once, so that
+/// `CIPHER_PASSPHRASE_FD` is read once and the user is prompted once even
+/// when several backends need the passphrase.
+fn cached_passphrase(
+    source: impl Fn() -> Result<String, AppError> + Send + Sync + 'static,
+) -> Arc<PassphraseSource> {
+    let cache = OnceLock::<String>::new();
+    Arc::new(move || {
+        if let Some(passphrase) = cache.get() {
+            return Ok(passphrase.clone());
+        }
+        let passphrase = source()?;
+        Ok(cache.get_or_init(|| passphrase).clone())
+    })
+}
+
+/// Exit codes for each kind of error so scripts can tell them apart.
+fn exit_code(error: &AppError) -> u8 {
+    match error {
+        AppError::Encryption { .. } => 1,
+        AppError::Usage(_) => 2,
+        AppError::Parse { .. } => 3,
+        AppError::Io { .. } => 4,
+        AppError::AccessDenied { .. } => 5,
+        AppError::Tampered { .. } => 6,
+        AppError::UnknownKey { .. } => 7,
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::from(exit_code(&error))
+        }
+    }
+}
+
+fn run() -> Result<(), AppError> {
+    let mut context = match env::var("CIPHER_CONTEXT") {
+        Ok(s) => encryption::parse_context(&s)?,
+        Err(_) => encryption::EncryptionContext::new(),
+    };
+    let mut reuse_data_key = env::var("CIPHER_REUSE_DATA_KEY").is_ok_and(|s| !s.is_empty());
+    let mut target_key = None;
+    let mut positional = Vec::new();
+    let mut raw_args = env::args().skip(1);
+    while let Some(arg) = raw_args.next() {
+        if arg == "--context" {
+            let value = raw_args
+                .next()
+                .ok_or_else(|| AppError::usage("missing value for --context"))?;
+            context.extend(encryption::parse_context(&value)?);
+        } else if arg == "--to" {
+            let value = raw_args
+                .next()
+                .ok_or_else(|| AppError::usage("missing value for --to"))?;
+            target_key = Some(value);
+        } else if arg == "--reuse-data-key" {
+            reuse_data_key = true;
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    let mut args = positional.into_iter();
     let command = args
-        .nth(1)
-        .ok_or_else(|| AppError::from_str("usage", "missing command"))?;
+        .next()
+        .ok_or_else(|| AppError::usage("missing command"))?;
     let input_file = args
         .next()
-        .ok_or_else(|| AppError::from_str("usage", "missing file name"))?;
-    let output_file = args.next().unwrap_or_else(|| input_file.clone());
+        .ok_or_else(|| AppError::usage("missing file name"))?;
+    let third_arg = args.next();
+    let output_file = third_arg.clone().unwrap_or_else(|| input_file.clone());
+
+    if command.as_str() == "keygen" {
+        return app::keygen_command(&input_file);
+    }
 
     let base_url = env::var("CIPHER_BASE_URL").ok();
 
-    let encryption_system = match env::var("CIPHER_KEY_ARN").ok() {
-        Some(s) if s == "DEBUG".to_string() => encryption::new_insecure_encryption()?,
-        Some(key) => encryption::create_kms_encryption(key.as_str(), &base_url)?,
-        _ => return Err(AppError::from_str("CIPHER_KEY_ARN", "no key provided")),
+    let key = match env::var("CIPHER_KEY_ARN").ok() {
+        Some(key) => key,
+        None if env::var("CIPHER_PASSPHRASE").is_ok()
+            || env::var("CIPHER_PASSPHRASE_FD").is_ok() =>
+        {
+            "pass:".to_string()
+        }
+        None => return Err(AppError::usage("no key provided in CIPHER_KEY_ARN")),
     };
+    // CIPHER_KEYS lists more keys that blocks may have been encrypted with
+    let mut keys = vec![key];
+    if let Ok(more) = env::var("CIPHER_KEYS") {
+        keys.extend(encryption::parse_key_list(&more));
+    }
+    let confirm = command == "encrypt" || command == "edit" || command == "set";
+    let mut options = BackendOptions::default();
+    options.base_url = base_url;
+    options.passphrase = cached_passphrase(move || get_passphrase(confirm));
+    options.reuse_data_key = reuse_data_key;
+    options.age_identity = env::var("CIPHER_AGE_IDENTITY").ok();
+    options.pgp_secret_key = env::var("CIPHER_PGP_SECRET_KEY").ok();
+    let encryption_system = encryption::create_composite_encryption(&keys, &options)?;
 
-    if command.as_str() == "cat" {
-        app::cat_command(&input_file, encryption_system.as_ref())
-    } else if command.as_str() == "decrypt" && output_file == app::STDIO {
-        app::cat_command(&input_file, encryption_system.as_ref())
+    if command.as_str() == "cat" || (command.as_str() == "decrypt" && output_file == app::STDIO) {
+        app::cat_command(&input_file, encryption_system.as_ref(), &context)
+    } else if command.as_str() == "get" {
+        let name = third_arg.ok_or_else(|| AppError::usage("get requires a block name"))?;
+        app::get_command(&input_file, &name, encryption_system.as_ref(), &context)
+    } else if command.as_str() == "set" {
+        let name = third_arg.ok_or_else(|| AppError::usage("set requires a block name"))?;
+        app::set_command(&input_file, &name, encryption_system.as_ref(), &context)
     } else if command.as_str() == "decrypt" && output_file == input_file {
-        Err(AppError::from_str(
-            "usage",
-            "decrypt requires an output file name",
-        ))
+        Err(AppError::usage("decrypt requires an output file name"))
     } else if command.as_str() == "decrypt" {
-        app::decrypt_command(&input_file, &output_file, encryption_system.as_ref())
+        app::decrypt_command(
+            &input_file,
+            &output_file,
+            encryption_system.as_ref(),
+            &context,
+        )
     } else if command.as_str() == "encrypt" {
-        app::encrypt_command(&input_file, &output_file, encryption_system.as_ref())
+        app::encrypt_command(
+            &input_file,
+            &output_file,
+            encryption_system.as_ref(),
+            &context,
+        )
     } else if command.as_str() == "rewind" {
-        app::rewind_command(&input_file, &output_file, encryption_system.as_ref())
+        app::rewind_command(
+            &input_file,
+            &output_file,
+            encryption_system.as_ref(),
+            &context,
+        )
+    } else if command.as_str() == "rotate" {
+        let target_key =
+            target_key.ok_or_else(|| AppError::usage("rotate requires a target key in --to"))?;
+        let mut target_options = options.clone();
+        target_options.passphrase = cached_passphrase(get_new_passphrase);
+        let target_system = encryption::create_encryption(&target_key, &target_options)?;
+        app::rotate_command(
+            &input_file,
+            &output_file,
+            encryption_system.as_ref(),
+            target_system.as_ref(),
+            &context,
+        )
     } else if command.as_str() == "edit" {
-        app::edit_command(&input_file, &output_file, encryption_system.as_ref())
+        app::edit_command(
+            &input_file,
+            &output_file,
+            encryption_system.as_ref(),
+            &context,
+        )
     } else {
-        Err(AppError::from_str(
-            "usage",
+        Err(AppError::usage(
             format!("invalid command: {}", command.as_str()).as_str(),
         ))
     }
@@ -0,0 +1,12 @@
+//! Helpers shared by the unit tests of several modules.
+
+/// Returns a path in the temporary directory, ending with `extension`, that no
+/// other test uses.
+pub(crate) fn temp_path(extension: &str) -> String {
+    format!(
+        "{}/cipher_test_{:016x}.{}",
+        std::env::temp_dir().display(),
+        rand::random::<u64>(),
+        extension
+    )
+}